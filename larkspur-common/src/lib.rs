@@ -5,11 +5,17 @@ use bytemuck::{Pod, Zeroable};
 #[repr(C)]
 #[derive(Copy, Clone, Debug, Pod, Zeroable)]
 pub struct Sample {
+    /// 线程 id
     pub pid:   u32,
+    /// 进程 id
+    pub tgid:  u32,
     pub cpu:   u32,
+    pub _pad:  u32,
     pub comm:  [i8; 16],
     pub kstack_id: i64,
     pub ustack_id: i64,
+    /// 采样时间，bpf_ktime_get_ns（CLOCK_MONOTONIC）
+    pub ts: u64,
 }
 
 #[repr(C)]
@@ -17,10 +23,37 @@ pub struct Sample {
 pub struct OffCpuSample {
     pub pid: u32,
     pub tgid: u32,
+    /// 切出时所在的 CPU
+    pub cpu: u32,
+    pub _pad: u32,
+    /// 切出时间，bpf_ktime_get_ns（CLOCK_MONOTONIC）
+    pub ts: u64,
     pub off_ns: u64,
     pub kstack_id: i64,
     pub ustack_id: i64,
     pub comm: [u8; 16],
+    pub waker: WakerInfo,
+}
+
+/// 唤醒阻塞线程的任务及其唤醒时的栈，`pid` 为 0 表示未知
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+pub struct WakerInfo {
+    pub pid: u32,
+    pub tgid: u32,
+    pub kstack_id: i64,
+    pub ustack_id: i64,
+    pub comm: [u8; 16],
+}
+
+impl WakerInfo {
+    pub const NONE: WakerInfo = WakerInfo {
+        pid: 0,
+        tgid: 0,
+        kstack_id: -1,
+        ustack_id: -1,
+        comm: [0; 16],
+    };
 }
 
 #[repr(C)]
@@ -28,4 +61,99 @@ pub struct OffCpuSample {
 pub struct TaskIdent {
     pub pid: u32,
     pub tgid: u32,
-}
\ No newline at end of file
+}
+/// 内核中按栈聚合时使用的键
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Pod, Zeroable)]
+pub struct StackIdent {
+    pub pid: u32,
+    pub tgid: u32,
+    pub kstack_id: i64,
+    pub ustack_id: i64,
+    /// 线程名，与逐个样本上报时一致
+    pub comm: [u8; 16],
+}
+
+/// 运行队列延迟的聚合键；`tgid` 为 0 表示该任务从未被观察到切出，进程未知
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Pod, Zeroable)]
+pub struct RunqKey {
+    pub tgid: u32,
+    pub _pad: u32,
+    pub cgroup_id: u64,
+    pub kstack_id: i64,
+    pub ustack_id: i64,
+}
+
+pub const HIST_SLOTS: usize = 32;
+
+/// 微秒级 log2 直方图：槽 0 为 0，槽 i 覆盖 [2^(i-1), 2^i)
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+pub struct LatencyHist {
+    pub total_ns: u64,
+    pub slots: [u64; HIST_SLOTS],
+}
+
+impl LatencyHist {
+    pub fn slot(us: u64) -> usize {
+        let bits = (u64::BITS - us.leading_zeros()) as usize;
+        if bits < HIST_SLOTS { bits } else { HIST_SLOTS - 1 }
+    }
+}
+
+/// 尚未释放的一次分配
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+pub struct AllocInfo {
+    pub size: u64,
+    pub ustack_id: i64,
+}
+
+/// 单个用户栈上累计的分配
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, Pod, Zeroable)]
+pub struct AllocTotal {
+    pub bytes: u64,
+    pub count: u64,
+}
+
+#[cfg(feature = "user")]
+mod user {
+    use super::*;
+
+    unsafe impl aya::Pod for StackIdent {}
+    unsafe impl aya::Pod for RunqKey {}
+    unsafe impl aya::Pod for LatencyHist {}
+    unsafe impl aya::Pod for AllocInfo {}
+    unsafe impl aya::Pod for AllocTotal {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slot_bounds() {
+        assert_eq!(LatencyHist::slot(0), 0);
+        assert_eq!(LatencyHist::slot(1), 1);
+        assert_eq!(LatencyHist::slot(2), 2);
+        assert_eq!(LatencyHist::slot(3), 2);
+        assert_eq!(LatencyHist::slot(4), 3);
+        assert_eq!(LatencyHist::slot(1023), 10);
+        assert_eq!(LatencyHist::slot(1024), 11);
+        // 超出范围的落在最后一个槽
+        assert_eq!(LatencyHist::slot(1 << 30), HIST_SLOTS - 1);
+        assert_eq!(LatencyHist::slot(1 << 31), HIST_SLOTS - 1);
+        assert_eq!(LatencyHist::slot(u64::MAX), HIST_SLOTS - 1);
+    }
+
+    #[test]
+    fn slot_ranges() {
+        // 槽 i 覆盖 [2^(i-1), 2^i)
+        for i in 1..HIST_SLOTS - 1 {
+            assert_eq!(LatencyHist::slot(1 << (i - 1)), i);
+            assert_eq!(LatencyHist::slot((1 << i) - 1), i);
+        }
+    }
+}
@@ -0,0 +1,120 @@
+#![no_std]
+#![no_main]
+
+use aya_ebpf::{
+    bindings::BPF_F_USER_STACK,
+    helpers::bpf_get_current_pid_tgid,
+    macros::{map, uprobe, uretprobe},
+    maps::{HashMap, PerCpuHashMap, StackTrace},
+    programs::{ProbeContext, RetProbeContext},
+};
+use larkspur_common::{AllocInfo, AllocTotal};
+
+// 进入分配函数时记录请求的大小，返回时取出
+#[map]
+static SIZES: HashMap<u64, u64> = HashMap::with_max_entries(10240, 0);
+
+// realloc 的旧地址，返回时确认成功后才释放
+#[map]
+static REALLOCS: HashMap<u64, u64> = HashMap::with_max_entries(10240, 0);
+
+// 地址 -> 尚未释放的分配，容量由用户态按 --max-allocs 设置
+#[map]
+static ALLOCS: HashMap<u64, AllocInfo> = HashMap::with_max_entries(262144, 0);
+
+// 用户栈 -> 累计分配
+#[map]
+static TOTALS: PerCpuHashMap<i64, AllocTotal> = PerCpuHashMap::with_max_entries(16384, 0);
+
+#[map]
+static USTACKS: StackTrace = StackTrace::with_max_entries(16384, 0);
+
+#[uprobe]
+pub fn malloc_enter(ctx: ProbeContext) -> u32 {
+    let size: u64 = ctx.arg(0).unwrap_or(0);
+    enter(size)
+}
+
+#[uprobe]
+pub fn calloc_enter(ctx: ProbeContext) -> u32 {
+    let nmemb: u64 = ctx.arg(0).unwrap_or(0);
+    let size: u64 = ctx.arg(1).unwrap_or(0);
+    enter(nmemb.saturating_mul(size))
+}
+
+#[uprobe]
+pub fn realloc_enter(ctx: ProbeContext) -> u32 {
+    let ptr: u64 = ctx.arg(0).unwrap_or(0);
+    let size: u64 = ctx.arg(1).unwrap_or(0);
+    // realloc 失败时旧地址仍有效，等返回后再决定是否释放
+    if ptr != 0 {
+        let _ = REALLOCS.insert(&bpf_get_current_pid_tgid(), &ptr, 0);
+    }
+    enter(size)
+}
+
+#[uretprobe]
+pub fn alloc_exit(ctx: RetProbeContext) -> u32 {
+    let key = bpf_get_current_pid_tgid();
+    let old = unsafe { REALLOCS.get(&key) }.copied();
+    let _ = REALLOCS.remove(&key);
+    let Some(&size) = (unsafe { SIZES.get(&key) }) else {
+        return 0;
+    };
+    let _ = SIZES.remove(&key);
+
+    let addr: u64 = ctx.ret().unwrap_or(0);
+    // realloc 成功（或 size 为 0 时按 free 处理）才释放旧地址
+    if let Some(old) = old
+        && (addr != 0 || size == 0)
+    {
+        release(old);
+    }
+    if addr == 0 {
+        return 0;
+    }
+
+    let ustack_id = unsafe { USTACKS.get_stackid(&ctx, BPF_F_USER_STACK as u64).unwrap_or_else(|e| e) };
+    let _ = ALLOCS.insert(&addr, &AllocInfo { size, ustack_id }, 0);
+
+    match TOTALS.get_ptr_mut(&ustack_id) {
+        Some(total) => unsafe {
+            (*total).bytes += size;
+            (*total).count += 1;
+        },
+        None => {
+            let _ = TOTALS.insert(&ustack_id, &AllocTotal { bytes: size, count: 1 }, 0);
+        }
+    }
+
+    0
+}
+
+#[uprobe]
+pub fn free_enter(ctx: ProbeContext) -> u32 {
+    release(ctx.arg(0).unwrap_or(0));
+    0
+}
+
+fn enter(size: u64) -> u32 {
+    let key = bpf_get_current_pid_tgid();
+    let _ = SIZES.insert(&key, &size, 0);
+    0
+}
+
+fn release(addr: u64) {
+    if addr != 0 {
+        let _ = ALLOCS.remove(&addr);
+    }
+}
+
+
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {
+    loop {}
+}
+
+#[unsafe(link_section = "license")]
+#[unsafe(no_mangle)]
+static LICENSE: [u8; 13] = *b"Dual MIT/GPL\0";
@@ -1,14 +1,15 @@
 #![no_std]
 #![no_main]
 
-use aya_ebpf::{macros::{map, raw_tracepoint},
-               maps::{HashMap, StackTrace, RingBuf},
-               programs::RawTracePointContext,
+use aya_ebpf::{macros::{map, tracepoint},
+               maps::{HashMap, PerCpuArray, PerCpuHashMap, StackTrace, RingBuf},
+               programs::TracePointContext,
                bindings::BPF_F_USER_STACK,
-               helpers::{bpf_probe_read_kernel, bpf_ktime_get_ns},
+               helpers::{bpf_get_smp_processor_id, bpf_ktime_get_ns},
                EbpfContext};
-use larkspur_common::{OffCpuSample, TaskIdent};
+use larkspur_common::{LatencyHist, OffCpuSample, StackIdent, TaskIdent, WakerInfo};
 
+// sched/sched_switch tracepoint 的参数布局
 #[repr(C)]
 struct SchedSwitch {
     common_type:   u16,
@@ -26,8 +27,43 @@ struct SchedSwitch {
     next_prio: i32,
 }
 
+// sched/sched_waking 参数中被唤醒任务的 pid 偏移
+const WAKING_PID_OFFSET: usize = 24;
+
+// 切出时记录的时间戳、所属进程与阻塞栈；切入时拿不到 next 的 tgid
+#[derive(Copy, Clone)]
+struct OffCpuStart {
+    ts: u64,
+    tgid: u32,
+    cpu: u32,
+    kstack_id: i64,
+    ustack_id: i64,
+}
+
+// 只记录该进程，0 表示所有进程；由用户态加载时设置
+#[unsafe(no_mangle)]
+static TARGET_PID: u32 = 0;
+
+// 只记录 prev_state 与该掩码相交的切出，0 表示包括被抢占在内的所有切出
+#[unsafe(no_mangle)]
+static STATE_MASK: i64 = 0;
+
+// 阻塞时长范围（纳秒），范围外的事件不上报
+#[unsafe(no_mangle)]
+static MIN_BLOCK_NS: u64 = 0;
+#[unsafe(no_mangle)]
+static MAX_BLOCK_NS: u64 = u64::MAX;
+
+// 非 0 时在内核中累计直方图，不再经过 ring buffer
+#[unsafe(no_mangle)]
+static HISTOGRAM: u8 = 0;
+
 #[map]
-static START: HashMap<TaskIdent, u64> = HashMap::with_max_entries(10240, 0);
+static START: HashMap<TaskIdent, OffCpuStart> = HashMap::with_max_entries(10240, 0);
+
+// 被唤醒任务 -> 唤醒者
+#[map]
+static WAKERS: HashMap<TaskIdent, WakerInfo> = HashMap::with_max_entries(10240, 0);
 
 #[map]
 static KSTACK: StackTrace = StackTrace::with_max_entries(16384, 0);
@@ -37,47 +73,97 @@ static USTACK: StackTrace = StackTrace::with_max_entries(16384, 0);
 #[map]
 static EVENTS: RingBuf = RingBuf::with_byte_size(256 * 1024, 0);
 
-#[raw_tracepoint(tracepoint="sched_switch")]
-pub fn off_cpu_trace(ctx: RawTracePointContext) -> u32{
+// ring buffer 写满时丢弃的事件数
+#[map]
+static DROPPED: PerCpuArray<u64> = PerCpuArray::with_max_entries(1, 0);
 
-    let _rec = ctx.as_ptr() as *const SchedSwitch;
+// 阻塞栈 -> 阻塞时长直方图
+#[map]
+static HISTS: PerCpuHashMap<StackIdent, LatencyHist> = PerCpuHashMap::with_max_entries(10240, 0);
 
-    let mut data: SchedSwitch = unsafe { core::mem::zeroed() };
+#[tracepoint]
+pub fn off_cpu_trace(ctx: TracePointContext) -> u32{
 
-    let _ = unsafe {
-        bpf_probe_read_kernel(&mut data as *mut _ as *mut u8)
+    let Ok(data) = (unsafe { ctx.read_at::<SchedSwitch>(0) }) else {
+        return 0;
     };
 
     let now = unsafe { bpf_ktime_get_ns() };
 
+    // tracepoint 触发时 current 仍是 prev，此时的栈就是它阻塞的位置
     let pid_prev = data.prev_pid as u32;
-    let tgid_prev = 0;
-    let key_prev = TaskIdent { pid: pid_prev, tgid: tgid_prev };
-    let _ = START.insert(&key_prev, &now, 0);
+    let target = unsafe { core::ptr::read_volatile(&TARGET_PID) };
+    let mask = unsafe { core::ptr::read_volatile(&STATE_MASK) };
+    let tgid_prev = ctx.tgid();
+    if pid_prev != 0
+        && (target == 0 || tgid_prev == target)
+        && (mask == 0 || data.prev_state & mask != 0)
+    {
+        let key_prev = TaskIdent { pid: pid_prev, tgid: 0 };
+        let start = OffCpuStart {
+            ts: now,
+            tgid: tgid_prev,
+            cpu: unsafe { bpf_get_smp_processor_id() },
+            kstack_id: unsafe { KSTACK.get_stackid(&ctx, 0).unwrap_or_else(|e| e) },
+            ustack_id: unsafe { USTACK.get_stackid(&ctx, BPF_F_USER_STACK as u64).unwrap_or_else(|e| e) },
+        };
+        let _ = START.insert(&key_prev, &start, 0);
+    }
 
+    // 切入时只知道 next 的 pid
     let pid_next = data.next_pid as u32;
-    let tgid_next = 0;
-    let key_next = TaskIdent { pid: pid_next, tgid: tgid_next };
+    let key_next = TaskIdent { pid: pid_next, tgid: 0 };
 
     unsafe {
-        if let Some(start_ns) = START.get(&key_next) {
-            let delta = now - *start_ns;
+        if let Some(start) = START.get(&key_next) {
+            let start = *start;
             START.remove(&key_next).ok();
 
-            let kstack_id = KSTACK.get_stackid(&ctx, 0).unwrap_or(-1);
-            let ustack_id = USTACK.get_stackid(&ctx, BPF_F_USER_STACK as u64).unwrap_or(-1);
+            let waker = match WAKERS.get(&key_next) {
+                Some(w) => {
+                    let w = *w;
+                    WAKERS.remove(&key_next).ok();
+                    w
+                }
+                None => WakerInfo::NONE,
+            };
+
+            let delta = now - start.ts;
+            if delta < core::ptr::read_volatile(&MIN_BLOCK_NS)
+                || delta > core::ptr::read_volatile(&MAX_BLOCK_NS)
+            {
+                return 0;
+            }
+
+            if core::ptr::read_volatile(&HISTOGRAM) != 0 {
+                let key = StackIdent {
+                    pid: pid_next,
+                    tgid: start.tgid,
+                    kstack_id: start.kstack_id,
+                    ustack_id: start.ustack_id,
+                    comm: data.next_comm,
+                };
+                record_hist(&key, delta);
+                return 0;
+            }
 
             if let Some(mut e) = EVENTS.reserve::<OffCpuSample>(0) {
                 let sample = OffCpuSample {
                     pid: pid_next,
-                    tgid: tgid_next,
+                    tgid: start.tgid,
+                    cpu: start.cpu,
+                    _pad: 0,
+                    ts: start.ts,
                     off_ns: delta,
-                    kstack_id,
-                    ustack_id,
+                    kstack_id: start.kstack_id,
+                    ustack_id: start.ustack_id,
                     comm: data.next_comm,
+                    waker,
                 };
                 e.write(sample);
                 e.submit(0);
+            } else if let Some(dropped) = DROPPED.get_ptr_mut(0) {
+                *dropped += 1;
             }
         }
     }
@@ -86,6 +172,44 @@ pub fn off_cpu_trace(ctx: RawTracePointContext) -> u32{
     0
 }
 
+fn record_hist(key: &StackIdent, delta: u64) {
+    if HISTS.get_ptr_mut(key).is_none() {
+        let _ = HISTS.insert(key, &unsafe { core::mem::zeroed() }, 0);
+    }
+    if let Some(hist) = HISTS.get_ptr_mut(key) {
+        let hist = unsafe { &mut *hist };
+        hist.total_ns += delta;
+        if let Some(count) = hist.slots.get_mut(LatencyHist::slot(delta / 1000)) {
+            *count += 1;
+        }
+    }
+}
+
+/// sched_waking 在唤醒者上下文中触发，记录唤醒者的栈
+#[tracepoint]
+pub fn wakeup_trace(ctx: TracePointContext) -> u32 {
+    let Ok(wakee) = (unsafe { ctx.read_at::<i32>(WAKING_PID_OFFSET) }) else {
+        return 0;
+    };
+    let key = TaskIdent { pid: wakee as u32, tgid: 0 };
+
+    // 只关心正在被跟踪的阻塞任务
+    if unsafe { START.get(&key) }.is_none() {
+        return 0;
+    }
+
+    let waker = WakerInfo {
+        pid: ctx.pid(),
+        tgid: ctx.tgid(),
+        kstack_id: unsafe { KSTACK.get_stackid(&ctx, 0).unwrap_or_else(|e| e) },
+        ustack_id: unsafe { USTACK.get_stackid(&ctx, BPF_F_USER_STACK as u64).unwrap_or_else(|e| e) },
+        comm: ctx.command().unwrap_or_default(),
+    };
+    let _ = WAKERS.insert(&key, &waker, 0);
+
+    0
+}
+
 
 #[cfg(not(test))]
 #[panic_handler]
@@ -2,8 +2,8 @@
 #![no_main]
 
 use aya_ebpf::bindings::BPF_F_USER_STACK;
-use aya_ebpf::{cty::c_char, helpers::{bpf_get_current_comm, bpf_get_current_pid_tgid, bpf_get_smp_processor_id}, macros::{map, perf_event}, maps::{PerCpuArray, RingBuf, StackTrace}, programs::PerfEventContext};
-use larkspur_common::Sample;
+use aya_ebpf::{cty::c_char, helpers::{bpf_get_current_comm, bpf_get_current_pid_tgid, bpf_get_smp_processor_id, bpf_ktime_get_ns}, macros::{map, perf_event}, maps::{PerCpuArray, PerCpuHashMap, RingBuf, StackTrace}, programs::PerfEventContext};
+use larkspur_common::{Sample, StackIdent};
 
 
 #[map]
@@ -18,19 +18,50 @@ static USTACKS: StackTrace = StackTrace::with_max_entries(16384, 0);
 #[map]
 static BUF: PerCpuArray<Sample> = PerCpuArray::with_max_entries(1, 0);
 
+// ring buffer 写满（或聚合表已满）时丢弃的样本数
+#[map]
+static DROPPED: PerCpuArray<u64> = PerCpuArray::with_max_entries(1, 0);
+
+// 非 0 时在内核中按栈计数，不再经过 ring buffer；由用户态加载时设置
+#[unsafe(no_mangle)]
+static AGGREGATE: u8 = 0;
+
+#[map]
+static COUNTS: PerCpuHashMap<StackIdent, u64> = PerCpuHashMap::with_max_entries(16384, 0);
+
 #[perf_event]
 pub fn on_cpu_trace(_ctx: PerfEventContext) -> u32 {
-    let pid = (bpf_get_current_pid_tgid() >> 32) as u32;
+    let pid_tgid = bpf_get_current_pid_tgid();
+    let tid = pid_tgid as u32;
+    let pid = (pid_tgid >> 32) as u32;
     let cpu = unsafe { bpf_get_smp_processor_id() };
 
+    if unsafe { core::ptr::read_volatile(&AGGREGATE) } != 0 {
+        let key = StackIdent {
+            pid: tid,
+            tgid: pid,
+            kstack_id: unsafe { STACKS.get_stackid(&_ctx, 0).unwrap_or_else(|e| e) },
+            ustack_id: unsafe { USTACKS.get_stackid(&_ctx, BPF_F_USER_STACK as u64).unwrap_or_else(|e| e) },
+            comm: bpf_get_current_comm().unwrap_or([0; 16]),
+        };
+        match COUNTS.get_ptr_mut(&key) {
+            Some(count) => unsafe { *count += 1 },
+            None if COUNTS.insert(&key, &1, 0).is_err() => count_dropped(),
+            None => {}
+        }
+        return 0;
+    }
+
     let sample = match BUF.get_ptr_mut(0) {
         Some(p) => p,
         None => return 1
     };
 
     unsafe {
-        (*sample).pid = pid;
+        (*sample).pid = tid;
+        (*sample).tgid = pid;
         (*sample).cpu = cpu;
+        (*sample).ts = bpf_ktime_get_ns();
         let _ = bpf_get_current_comm().map(|b| {
             for (i, &v) in b.iter().enumerate() {
                 (*sample).comm[i] = v as c_char;
@@ -38,8 +69,8 @@ pub fn on_cpu_trace(_ctx: PerfEventContext) -> u32 {
         });
     }
 
-    let kstack_id = unsafe { STACKS.get_stackid(&_ctx, 0).unwrap_or(-1) };
-    let ustack_id = unsafe { USTACKS.get_stackid(&_ctx, BPF_F_USER_STACK as u64).unwrap_or(-1) };
+    let kstack_id = unsafe { STACKS.get_stackid(&_ctx, 0).unwrap_or_else(|e| e) };
+    let ustack_id = unsafe { USTACKS.get_stackid(&_ctx, BPF_F_USER_STACK as u64).unwrap_or_else(|e| e) };
 
     unsafe {
         (*sample).kstack_id = kstack_id;
@@ -49,11 +80,19 @@ pub fn on_cpu_trace(_ctx: PerfEventContext) -> u32 {
     if let Some(mut entry) = SAMPLES.reserve::<Sample>(0) {
         unsafe { entry.write(*sample); }
         entry.submit(0);
+    } else {
+        count_dropped();
     }
 
     0
 }
 
+fn count_dropped() {
+    if let Some(dropped) = DROPPED.get_ptr_mut(0) {
+        unsafe { *dropped += 1; }
+    }
+}
+
 
 #[cfg(not(test))]
 #[panic_handler]
@@ -0,0 +1,146 @@
+#![no_std]
+#![no_main]
+
+use aya_ebpf::{
+    EbpfContext,
+    bindings::BPF_F_USER_STACK,
+    helpers::{bpf_get_current_cgroup_id, bpf_ktime_get_ns},
+    macros::{map, tracepoint},
+    maps::{HashMap, PerCpuHashMap, StackTrace},
+    programs::TracePointContext,
+};
+use larkspur_common::{LatencyHist, RunqKey, TaskIdent};
+
+// sched/sched_switch tracepoint 的参数布局
+#[repr(C)]
+struct SchedSwitch {
+    common_type:   u16,
+    common_flags:  u8,
+    common_preempt_count: u8,
+    common_pid:    i32,
+
+    prev_comm: [u8; 16],
+    prev_pid:  i32,
+    prev_prio: i32,
+    prev_state: i64,
+
+    next_comm: [u8; 16],
+    next_pid:  i32,
+    next_prio: i32,
+}
+
+// sched/sched_wakeup(_new) 参数中被唤醒任务的 pid 偏移
+const WAKEUP_PID_OFFSET: usize = 24;
+
+// 4.14 起 sched_switch 把被抢占的任务报告为 TASK_REPORT_MAX，而不是 0
+const TASK_REPORT_MAX: i64 = 0x100;
+
+// 只统计该进程，0 表示所有进程；由用户态加载时设置
+#[unsafe(no_mangle)]
+static TARGET_PID: u32 = 0;
+
+// 非 0 时按任务上次切出时的栈细分
+#[unsafe(no_mangle)]
+static PER_STACK: u8 = 0;
+
+// 任务变为可运行的时间
+#[map]
+static ENQUEUED: HashMap<TaskIdent, u64> = HashMap::with_max_entries(10240, 0);
+
+// 切入时只知道 pid，所以在任务切出（它是 current）时记下所属进程、cgroup 和栈，
+// 切入时删除
+#[map]
+static TASKS: HashMap<TaskIdent, RunqKey> = HashMap::with_max_entries(10240, 0);
+
+#[map]
+static HISTS: PerCpuHashMap<RunqKey, LatencyHist> = PerCpuHashMap::with_max_entries(10240, 0);
+
+#[map]
+static KSTACK: StackTrace = StackTrace::with_max_entries(16384, 0);
+#[map]
+static USTACK: StackTrace = StackTrace::with_max_entries(16384, 0);
+
+#[tracepoint]
+pub fn runq_wakeup(ctx: TracePointContext) -> u32 {
+    let Ok(pid) = (unsafe { ctx.read_at::<i32>(WAKEUP_PID_OFFSET) }) else {
+        return 0;
+    };
+    let key = TaskIdent { pid: pid as u32, tgid: 0 };
+    // 只跟踪某个进程时，只有切出时被记录过的任务才属于它
+    let target = unsafe { core::ptr::read_volatile(&TARGET_PID) };
+    if target != 0 && unsafe { TASKS.get(&key) }.is_none() {
+        return 0;
+    }
+    let now = unsafe { bpf_ktime_get_ns() };
+    let _ = ENQUEUED.insert(&key, &now, 0);
+    0
+}
+
+#[tracepoint]
+pub fn runq_switch(ctx: TracePointContext) -> u32 {
+    let Ok(data) = (unsafe { ctx.read_at::<SchedSwitch>(0) }) else {
+        return 0;
+    };
+    let now = unsafe { bpf_ktime_get_ns() };
+
+    let target = unsafe { core::ptr::read_volatile(&TARGET_PID) };
+    let key_prev = TaskIdent { pid: data.prev_pid as u32, tgid: 0 };
+    if data.prev_pid != 0 && (target == 0 || ctx.tgid() == target) {
+        let per_stack = unsafe { core::ptr::read_volatile(&PER_STACK) } != 0;
+        let task = RunqKey {
+            tgid: ctx.tgid(),
+            _pad: 0,
+            cgroup_id: unsafe { bpf_get_current_cgroup_id() },
+            kstack_id: if per_stack { unsafe { KSTACK.get_stackid(&ctx, 0).unwrap_or_else(|e| e) } } else { -1 },
+            ustack_id: if per_stack {
+                unsafe { USTACK.get_stackid(&ctx, BPF_F_USER_STACK as u64).unwrap_or_else(|e| e) }
+            } else {
+                -1
+            },
+        };
+        let _ = TASKS.insert(&key_prev, &task, 0);
+
+        // 被抢占的任务仍可运行，立即重新排队
+        if data.prev_state & 0xff == 0 || data.prev_state & TASK_REPORT_MAX != 0 {
+            let _ = ENQUEUED.insert(&key_prev, &now, 0);
+        }
+    }
+
+    let key_next = TaskIdent { pid: data.next_pid as u32, tgid: 0 };
+    let task = unsafe { TASKS.get(&key_next) }.copied();
+    let _ = TASKS.remove(&key_next);
+    let Some(&enqueued) = (unsafe { ENQUEUED.get(&key_next) }) else {
+        return 0;
+    };
+    let _ = ENQUEUED.remove(&key_next);
+
+    let key = task.unwrap_or(RunqKey { tgid: 0, _pad: 0, cgroup_id: 0, kstack_id: -1, ustack_id: -1 });
+    if target != 0 && key.tgid != target {
+        return 0;
+    }
+
+    let delta = now - enqueued;
+    if HISTS.get_ptr_mut(&key).is_none() {
+        let _ = HISTS.insert(&key, &unsafe { core::mem::zeroed() }, 0);
+    }
+    if let Some(hist) = HISTS.get_ptr_mut(&key) {
+        let hist = unsafe { &mut *hist };
+        hist.total_ns += delta;
+        if let Some(count) = hist.slots.get_mut(LatencyHist::slot(delta / 1000)) {
+            *count += 1;
+        }
+    }
+
+    0
+}
+
+
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {
+    loop {}
+}
+
+#[unsafe(link_section = "license")]
+#[unsafe(no_mangle)]
+static LICENSE: [u8; 13] = *b"Dual MIT/GPL\0";
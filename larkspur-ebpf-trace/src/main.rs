@@ -0,0 +1,87 @@
+#![no_std]
+#![no_main]
+
+use aya_ebpf::{
+    EbpfContext,
+    bindings::BPF_F_USER_STACK,
+    cty::c_char,
+    helpers::{bpf_get_smp_processor_id, bpf_ktime_get_ns},
+    macros::{kprobe, map, tracepoint, uprobe},
+    maps::{PerCpuArray, RingBuf, StackTrace},
+    programs::{ProbeContext, TracePointContext},
+};
+use larkspur_common::Sample;
+
+// 只记录该进程，0 表示所有进程；由用户态加载时设置
+#[unsafe(no_mangle)]
+static TARGET_PID: u32 = 0;
+
+#[map]
+static SAMPLES: RingBuf = RingBuf::with_byte_size(256 * 1024, 0);
+
+#[map]
+static STACKS: StackTrace = StackTrace::with_max_entries(16384, 0);
+
+#[map]
+static USTACKS: StackTrace = StackTrace::with_max_entries(16384, 0);
+
+// ring buffer 写满时丢弃的样本数
+#[map]
+static DROPPED: PerCpuArray<u64> = PerCpuArray::with_max_entries(1, 0);
+
+#[tracepoint]
+pub fn trace_tracepoint(ctx: TracePointContext) -> u32 {
+    record(&ctx)
+}
+
+#[kprobe]
+pub fn trace_kprobe(ctx: ProbeContext) -> u32 {
+    record(&ctx)
+}
+
+#[uprobe]
+pub fn trace_uprobe(ctx: ProbeContext) -> u32 {
+    record(&ctx)
+}
+
+fn record<C: EbpfContext>(ctx: &C) -> u32 {
+    let pid = ctx.tgid();
+    let target = unsafe { core::ptr::read_volatile(&TARGET_PID) };
+    if target != 0 && pid != target {
+        return 0;
+    }
+
+    let kstack_id = unsafe { STACKS.get_stackid(ctx, 0).unwrap_or_else(|e| e) };
+    let ustack_id = unsafe { USTACKS.get_stackid(ctx, BPF_F_USER_STACK as u64).unwrap_or_else(|e| e) };
+
+    let sample = Sample {
+        pid: ctx.pid(),
+        tgid: pid,
+        cpu: unsafe { bpf_get_smp_processor_id() },
+        _pad: 0,
+        comm: ctx.command().unwrap_or_default().map(|c| c as c_char),
+        kstack_id,
+        ustack_id,
+        ts: unsafe { bpf_ktime_get_ns() },
+    };
+
+    if let Some(mut entry) = SAMPLES.reserve::<Sample>(0) {
+        entry.write(sample);
+        entry.submit(0);
+    } else if let Some(dropped) = DROPPED.get_ptr_mut(0) {
+        unsafe { *dropped += 1; }
+    }
+
+    0
+}
+
+
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {
+    loop {}
+}
+
+#[unsafe(link_section = "license")]
+#[unsafe(no_mangle)]
+static LICENSE: [u8; 13] = *b"Dual MIT/GPL\0";
@@ -0,0 +1,146 @@
+use std::collections::BTreeSet;
+
+use anyhow::{Result, bail};
+use aya::{
+    EbpfLoader,
+    maps::{HashMap, PerCpuHashMap, StackTraceMap},
+    programs::UProbe,
+};
+use larkspur_common::{AllocInfo, AllocTotal};
+use log::{info, warn};
+use proc_maps::{Pid, get_process_maps};
+use crate::symbolize::{kstack, ustack};
+use crate::collector::{deadline, stacktrace_from_id, wait_for_stop};
+use crate::profile::{Aggregator, Profile, StackKey, Unit, process_comm};
+
+/// 分配函数与对应的入口探针；malloc/calloc/realloc 返回时统一由 `alloc_exit` 处理
+const HOOKS: &[(&str, &str)] = &[
+    ("malloc", "malloc_enter"),
+    ("calloc", "calloc_enter"),
+    ("realloc", "realloc_enter"),
+    ("free", "free_enter"),
+];
+
+/// 按库文件名前缀识别的分配器
+const ALLOCATORS: &[&str] = &["libc.so", "libjemalloc", "libtcmalloc"];
+
+pub struct AllocProfile {
+    /// 采集期间每个栈累计分配的字节数
+    pub allocated: Profile,
+    /// 结束时仍未释放的字节数
+    pub outstanding: Profile,
+}
+
+pub async fn run(pid: u32, duration: u64, max_allocs: u32) -> Result<AllocProfile> {
+    let mut bpf = EbpfLoader::new()
+        .set_max_entries("ALLOCS", max_allocs)
+        .load(aya::include_bytes_aligned!(concat!(
+            env!("OUT_DIR"),
+            "/larkspur-alloc"
+        )))?;
+
+    let libs = allocator_libs(pid as Pid)?;
+    for (_, prog) in HOOKS {
+        let prog: &mut UProbe = bpf.program_mut(prog).unwrap().try_into()?;
+        prog.load()?;
+    }
+    let exit: &mut UProbe = bpf.program_mut("alloc_exit").unwrap().try_into()?;
+    exit.load()?;
+
+    for lib in &libs {
+        for &(symbol, prog) in HOOKS {
+            let entry: &mut UProbe = bpf.program_mut(prog).unwrap().try_into()?;
+            let link = match entry.attach(Some(symbol), 0, lib, Some(pid as i32)) {
+                Ok(link) => link,
+                Err(e) => {
+                    warn!("failed to attach {symbol} in {lib}: {e}");
+                    continue;
+                }
+            };
+            if symbol != "free" {
+                let exit: &mut UProbe = bpf.program_mut("alloc_exit").unwrap().try_into()?;
+                if let Err(e) = exit.attach(Some(symbol), 0, lib, Some(pid as i32)) {
+                    warn!("failed to attach the return probe of {symbol} in {lib}: {e}");
+                    // 没有返回探针时入口记下的大小永远不会被取走
+                    let entry: &mut UProbe = bpf.program_mut(prog).unwrap().try_into()?;
+                    entry.detach(link)?;
+                }
+            }
+        }
+        info!("tracing allocations in {lib}");
+    }
+
+    let mut u_resolvers = ustack::ResolverCache::with_pid(pid as Pid)?;
+    let k_resolver = kstack::KStackResolver::new()?;
+
+    wait_for_stop(deadline(duration)).await?;
+
+    for name in HOOKS.iter().map(|(_, p)| *p).chain(["alloc_exit"]) {
+        let prog: &mut UProbe = bpf.program_mut(name).unwrap().try_into()?;
+        prog.unload()?;
+    }
+
+    let mut ustacks = StackTraceMap::try_from(bpf.take_map("USTACKS").unwrap())?;
+    let totals = PerCpuHashMap::<_, i64, AllocTotal>::try_from(bpf.take_map("TOTALS").unwrap())?;
+    let allocs = HashMap::<_, u64, AllocInfo>::try_from(bpf.take_map("ALLOCS").unwrap())?;
+
+    let comm = process_comm(pid);
+    let mut key = |ustack_id: i64| StackKey {
+        pid,
+        tid: 0,
+        comm: comm.clone(),
+        kaddrs: Vec::new(),
+        uaddrs: stacktrace_from_id(&mut ustacks, ustack_id),
+        waker: None,
+    };
+
+    let mut allocated = Aggregator::default();
+    let (mut alloc_bytes, mut alloc_count) = (0u64, 0u64);
+    for entry in totals.iter() {
+        let (ustack_id, per_cpu) = entry?;
+        let bytes: u64 = per_cpu.iter().map(|t| t.bytes).sum();
+        alloc_bytes += bytes;
+        alloc_count += per_cpu.iter().map(|t| t.count).sum::<u64>();
+        allocated.add(key(ustack_id), bytes);
+    }
+
+    let mut outstanding = Aggregator::default();
+    let (mut live_bytes, mut live_count) = (0u64, 0u64);
+    for entry in allocs.iter() {
+        let (_, info) = entry?;
+        live_bytes += info.size;
+        live_count += 1;
+        outstanding.add(key(info.ustack_id), info.size);
+    }
+
+    info!("allocated {alloc_bytes} bytes in {alloc_count} calls, {live_bytes} bytes in {live_count} allocations still outstanding");
+    if live_count as u32 >= max_allocs {
+        warn!("outstanding allocation table is full, consider increasing --max-allocs");
+    }
+
+    let mut profile = AllocProfile {
+        allocated: allocated.symbolize(&k_resolver, &mut u_resolvers),
+        outstanding: outstanding.symbolize(&k_resolver, &mut u_resolvers),
+    };
+    profile.allocated.unit = Unit::Bytes;
+    profile.outstanding.unit = Unit::Bytes;
+    Ok(profile)
+}
+
+/// 从目标进程的内存映射中找出 libc 以及 jemalloc / tcmalloc
+fn allocator_libs(pid: Pid) -> Result<BTreeSet<String>> {
+    let libs: BTreeSet<String> = get_process_maps(pid)?
+        .iter()
+        .filter_map(|m| m.filename())
+        .filter(|p| {
+            p.file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| ALLOCATORS.iter().any(|a| n.starts_with(a)))
+        })
+        .filter_map(|p| p.to_str().map(str::to_string))
+        .collect();
+    if libs.is_empty() {
+        bail!("no allocator library (libc, jemalloc, tcmalloc) is mapped into pid {pid}");
+    }
+    Ok(libs)
+}
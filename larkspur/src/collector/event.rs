@@ -0,0 +1,175 @@
+use std::{fmt, str::FromStr};
+
+use anyhow::{Result, anyhow};
+use aya::{
+    programs::{
+        PerfEvent, PerfEventScope, PerfTypeId, ProgramError, SamplePolicy,
+        perf_event::{PerfEventLinkId, perf_hw_id, perf_sw_ids},
+    },
+    util::online_cpus,
+};
+use log::warn;
+
+/// on-cpu 采样的触发事件
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+    CpuClock,
+    PageFaults,
+    ContextSwitches,
+    Cycles,
+    Instructions,
+    CacheMisses,
+    BranchMisses,
+    /// PMU 原始事件编码，`r<hex>`
+    Raw(u64),
+}
+
+impl Event {
+    fn perf_type(&self) -> (PerfTypeId, u64) {
+        match *self {
+            Event::CpuClock => (PerfTypeId::Software, perf_sw_ids::PERF_COUNT_SW_CPU_CLOCK as u64),
+            Event::PageFaults => (PerfTypeId::Software, perf_sw_ids::PERF_COUNT_SW_PAGE_FAULTS as u64),
+            Event::ContextSwitches => (PerfTypeId::Software, perf_sw_ids::PERF_COUNT_SW_CONTEXT_SWITCHES as u64),
+            Event::Cycles => (PerfTypeId::Hardware, perf_hw_id::PERF_COUNT_HW_CPU_CYCLES as u64),
+            Event::Instructions => (PerfTypeId::Hardware, perf_hw_id::PERF_COUNT_HW_INSTRUCTIONS as u64),
+            Event::CacheMisses => (PerfTypeId::Hardware, perf_hw_id::PERF_COUNT_HW_CACHE_MISSES as u64),
+            Event::BranchMisses => (PerfTypeId::Hardware, perf_hw_id::PERF_COUNT_HW_BRANCH_MISSES as u64),
+            Event::Raw(config) => (PerfTypeId::Raw, config),
+        }
+    }
+
+    /// 是否依赖硬件 PMU
+    pub fn is_hardware(&self) -> bool {
+        matches!(self.perf_type().0, PerfTypeId::Hardware | PerfTypeId::Raw)
+    }
+}
+
+impl FromStr for Event {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "cpu-clock" => Event::CpuClock,
+            "page-faults" | "faults" => Event::PageFaults,
+            "context-switches" | "cs" => Event::ContextSwitches,
+            "cycles" | "cpu-cycles" => Event::Cycles,
+            "instructions" => Event::Instructions,
+            "cache-misses" => Event::CacheMisses,
+            "branch-misses" => Event::BranchMisses,
+            _ => {
+                let hex = s.strip_prefix('r').ok_or_else(|| anyhow!("unknown event `{s}`"))?;
+                Event::Raw(u64::from_str_radix(hex, 16).map_err(|_| anyhow!("invalid raw event `{s}`"))?)
+            }
+        })
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Event::CpuClock => f.write_str("cpu-clock"),
+            Event::PageFaults => f.write_str("page-faults"),
+            Event::ContextSwitches => f.write_str("context-switches"),
+            Event::Cycles => f.write_str("cycles"),
+            Event::Instructions => f.write_str("instructions"),
+            Event::CacheMisses => f.write_str("cache-misses"),
+            Event::BranchMisses => f.write_str("branch-misses"),
+            Event::Raw(config) => write!(f, "r{config:x}"),
+        }
+    }
+}
+
+/// 回退到 cpu-clock 时使用的采样频率（Hz）
+pub const DEFAULT_FREQUENCY: u64 = 99;
+
+/// 在所有在线 CPU 上挂载 `event`，`pid` 为 None 时采样所有进程；
+/// 硬件事件不可用（如虚拟机中没有 PMU）时回退到 cpu-clock。返回实际使用的事件，
+/// 回退时按事件计数的 `policy` 改为默认频率
+pub fn attach(prog: &mut PerfEvent, event: Event, pid: Option<u32>, policy: &mut SamplePolicy) -> Result<Event> {
+    match attach_all(prog, event, pid, policy) {
+        Ok(()) => Ok(event),
+        Err(e) if event.is_hardware() && pmu_unavailable(&e) => {
+            warn!("event {event} is not supported here ({e}), falling back to cpu-clock");
+            // 硬件事件的计数周期当作 cpu-clock 的纳秒数会采得过密
+            if let SamplePolicy::Period(period) = *policy {
+                warn!("period {period} was chosen for {event}, sampling cpu-clock at {DEFAULT_FREQUENCY} Hz instead");
+                *policy = SamplePolicy::Frequency(DEFAULT_FREQUENCY);
+            }
+            attach_all(prog, Event::CpuClock, pid, policy)?;
+            Ok(Event::CpuClock)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn attach_all(prog: &mut PerfEvent, event: Event, pid: Option<u32>, policy: &SamplePolicy) -> Result<(), ProgramError> {
+    let (perf_type, config) = event.perf_type();
+    let cpus = online_cpus().map_err(|(_, e)| ProgramError::IOError(e))?;
+
+    let mut links: Vec<PerfEventLinkId> = Vec::with_capacity(cpus.len());
+    for cpu in cpus {
+        let scope = match pid {
+            Some(pid) => PerfEventScope::OneProcessOneCpu { pid, cpu },
+            None => PerfEventScope::AllProcessesOneCpu { cpu },
+        };
+        match prog.attach(perf_type.clone(), config, scope, policy.clone(), false) {
+            Ok(link) => links.push(link),
+            Err(e) => {
+                // 部分 CPU 挂载失败时撤销已挂载的，避免混用两种事件
+                for link in links {
+                    let _ = prog.detach(link);
+                }
+                return Err(e);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn pmu_unavailable(e: &ProgramError) -> bool {
+    let ProgramError::SyscallError(e) = e else {
+        return false;
+    };
+    matches!(
+        e.io_error.raw_os_error(),
+        Some(libc::ENOENT | libc::ENODEV | libc::EOPNOTSUPP)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_round_trip() {
+        for event in [
+            Event::CpuClock,
+            Event::PageFaults,
+            Event::ContextSwitches,
+            Event::Cycles,
+            Event::Instructions,
+            Event::CacheMisses,
+            Event::BranchMisses,
+            Event::Raw(0x1a2b),
+        ] {
+            assert_eq!(event.to_string().parse::<Event>().unwrap(), event);
+        }
+    }
+
+    #[test]
+    fn aliases() {
+        assert_eq!("faults".parse::<Event>().unwrap(), Event::PageFaults);
+        assert_eq!("cs".parse::<Event>().unwrap(), Event::ContextSwitches);
+        assert_eq!("cpu-cycles".parse::<Event>().unwrap(), Event::Cycles);
+    }
+
+    #[test]
+    fn raw_events() {
+        assert_eq!("r003c".parse::<Event>().unwrap(), Event::Raw(0x3c));
+        assert_eq!("rC0".parse::<Event>().unwrap(), Event::Raw(0xc0));
+        assert!(Event::Raw(0).is_hardware());
+        assert!("r".parse::<Event>().is_err());
+        assert!("rxyz".parse::<Event>().is_err());
+        assert!("bogus".parse::<Event>().is_err());
+    }
+}
@@ -1,13 +1,227 @@
-use aya::maps::StackTraceMap;
+use std::{
+    future,
+    time::{Duration, Instant},
+};
 
+use anyhow::{Result, bail};
+use aya::maps::{MapData, PerCpuArray, RingBuf, StackTraceMap};
+use log::info;
+use tokio::{
+    io::{Interest, unix::AsyncFd},
+    signal::{
+        self,
+        unix::{self, SignalKind},
+    },
+    time,
+};
+
+use crate::metrics;
+use crate::profile::Aggregator;
+
+pub mod event;
 pub mod on_cpu;
 pub mod off_cpu;
+pub mod trace;
+pub mod alloc;
+pub mod wall;
+pub mod runqlat;
+pub mod stream;
+
+/// 逐个样本经 ring buffer 上报的采集在用户态的处理选项
+#[derive(Copy, Clone, Debug, Default)]
+pub struct SampleOpts {
+    /// 按线程拆分
+    pub threads: bool,
+    /// 保留每个样本的时间，用于输出时间线和逐样本的 JSON
+    pub timeline: bool,
+    /// 只保留采集开始后第 `from` 到第 `to` 秒之间的样本
+    pub from: Option<f64>,
+    pub to: Option<f64>,
+}
+
+impl SampleOpts {
+    /// 不按线程拆分时线程 id 记为 0，使同一栈的样本合并
+    pub fn tid(&self, tid: u32) -> u32 {
+        if self.threads { tid } else { 0 }
+    }
+
+    /// 以当前时刻作为采集起点
+    pub fn aggregator(&self) -> Result<Aggregator> {
+        let window = match (self.from, self.to) {
+            (None, None) => None,
+            (from, to) => {
+                let from = from.unwrap_or(0.0);
+                let to = to.unwrap_or(f64::INFINITY);
+                if from < 0.0 || from >= to {
+                    bail!("--from must be non-negative and less than --to");
+                }
+                Some(((from * 1e9) as u64, (to * 1e9) as u64))
+            }
+        };
+        Ok(Aggregator::new(monotonic_ns(), window, self.timeline))
+    }
+}
+
+/// 与 bpf_ktime_get_ns 相同的时钟（CLOCK_MONOTONIC），单位纳秒
+pub fn monotonic_ns() -> u64 {
+    let mut ts = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
 
+/// 负的 id 是 bpf_get_stackid 返回的 errno：-1 表示未取栈，-EFAULT 表示栈为空
+/// （如内核线程没有用户栈），其余视为取栈失败
 pub fn stacktrace_from_id(map: &mut StackTraceMap<aya::maps::MapData>, id: i64) -> Vec<u64> {
     if id < 0 {
+        if id != -1 && id != -(libc::EFAULT as i64) {
+            metrics::stack_error();
+        }
         return Vec::new();
     }
-    map.get(&(id as u32), 0)
-        .map(|t| t.frames().iter().map(|f| f.ip).collect())
+    match map.get(&(id as u32), 0) {
+        Ok(t) => t.frames().iter().map(|f| f.ip).collect(),
+        Err(_) => {
+            // 栈表已满或同一 id 已被其他栈覆盖
+            metrics::stack_error();
+            Vec::new()
+        }
+    }
+}
+
+/// 把 KiB 换算成字节，内核要求 ring buffer 大小是页大小的 2 的幂次倍
+pub fn ring_bytes(size_kib: u32) -> Result<u32> {
+    let size = size_kib.checked_mul(1024).unwrap_or(0);
+    if !size.is_power_of_two() || size < 4096 {
+        bail!("ring buffer size must be a power of two and at least 4 KiB, got {size_kib} KiB");
+    }
+    Ok(size)
+}
+
+/// 每次唤醒最多处理的记录数，避免持续高负载时饿死截止时间检查
+const DRAIN_BATCH: usize = 1024;
+
+/// `duration` 为 0 时表示一直采集到被中断
+pub fn deadline(duration: u64) -> Option<Instant> {
+    (duration > 0).then(|| Instant::now() + Duration::from_secs(duration))
+}
+
+/// 等待 SIGINT 或 SIGTERM
+pub async fn shutdown_signal() -> Result<()> {
+    let mut term = unix::signal(SignalKind::terminate())?;
+    tokio::select! {
+        r = signal::ctrl_c() => r?,
+        _ = term.recv() => {}
+    }
+    Ok(())
+}
+
+async fn expired(deadline: Option<Instant>) {
+    match deadline {
+        Some(d) => time::sleep_until(d.into()).await,
+        None => future::pending().await,
+    }
+}
+
+/// 用于只读 map、不消费 ring buffer 的采集：等到截止时间或收到 SIGINT/SIGTERM
+pub async fn wait_for_stop(deadline: Option<Instant>) -> Result<()> {
+    tokio::select! {
+        _ = expired(deadline) => Ok(()),
+        r = shutdown_signal() => {
+            r?;
+            info!("interrupted, stopping collection");
+            Ok(())
+        }
+    }
+}
+
+/// 事件驱动地消费 ring buffer：无数据时休眠，直到截止时间或收到 SIGINT/SIGTERM。
+/// 停止时先调用 `detach` 卸载程序，再读完缓冲区里剩余的记录。返回处理的记录数
+pub async fn consume_ring<F, D>(
+    ring: RingBuf<MapData>,
+    deadline: Option<Instant>,
+    handle: F,
+    detach: D,
+) -> Result<u64>
+where
+    F: FnMut(&[u8]),
+    D: FnOnce() -> Result<()>,
+{
+    let stop = async {
+        tokio::select! {
+            _ = expired(deadline) => Ok(()),
+            r = shutdown_signal() => {
+                r?;
+                info!("interrupted, stopping collection");
+                Ok(())
+            }
+        }
+    };
+    consume_ring_until(ring, stop, handle, detach).await
+}
+
+/// 同 [`consume_ring`]，但在 `stop` 完成时停止
+pub async fn consume_ring_until<S, F, D>(
+    ring: RingBuf<MapData>,
+    stop: S,
+    mut handle: F,
+    detach: D,
+) -> Result<u64>
+where
+    S: Future<Output = Result<()>>,
+    F: FnMut(&[u8]),
+    D: FnOnce() -> Result<()>,
+{
+    let mut fd = AsyncFd::with_interest(ring, Interest::READABLE)?;
+    tokio::pin!(stop);
+
+    let mut collected = 0u64;
+    loop {
+        tokio::select! {
+            r = &mut stop => {
+                r?;
+                break;
+            }
+            guard = fd.readable_mut() => {
+                let mut guard = guard?;
+                let ring = guard.get_inner_mut();
+                let mut batch = 0;
+                while batch < DRAIN_BATCH {
+                    let Some(record) = ring.next() else { break };
+                    handle(&record);
+                    batch += 1;
+                }
+                collected += batch as u64;
+                // 批次未满说明已读空，等待下一次可读通知
+                if batch < DRAIN_BATCH {
+                    guard.clear_ready();
+                }
+            }
+        }
+    }
+
+    detach()?;
+
+    let ring = fd.get_mut();
+    while let Some(record) = ring.next() {
+        handle(&record);
+        collected += 1;
+    }
+    Ok(collected)
+}
+
+/// 汇总各 CPU 上因 ring buffer 写满而丢弃的样本数
+pub fn dropped_count(map: &PerCpuArray<MapData, u64>) -> u64 {
+    map.get(&0, 0)
+        .map(|values| values.iter().sum())
         .unwrap_or_default()
-}
\ No newline at end of file
+}
+
+pub fn report_lost(collector: &'static str, collected: u64, lost: u64) {
+    metrics::samples(collector, collected, lost);
+    let total = collected + lost;
+    let ratio = if total == 0 { 0.0 } else { lost as f64 * 100.0 / total as f64 };
+    log::info!("collected {collected} samples, lost {lost} ({ratio:.2}%)");
+    if lost > 0 {
+        log::warn!("ring buffer overflowed, consider increasing --ring-buf-size");
+    }
+}
@@ -1,59 +1,203 @@
-use anyhow::Result;
-use aya::{programs::RawTracePoint, maps::{RingBuf, StackTraceMap}};
+use std::{cmp::Reverse, collections::HashMap};
+
+use anyhow::{Result, bail};
+use aya::{Ebpf, EbpfLoader, programs::TracePoint, maps::{PerCpuArray, PerCpuHashMap, RingBuf, StackTraceMap}};
 use proc_maps::Pid;
-use tokio::task;
-use std::time::{Duration, Instant};
-use log::info;
 use crate::symbolize::{kstack, ustack};
-use larkspur_common::OffCpuSample;
-use crate::collector::stacktrace_from_id;
+use larkspur_common::{HIST_SLOTS, LatencyHist, OffCpuSample, StackIdent};
+use crate::metrics;
+use crate::collector::stream::{self, SampleEvent, SampleKind, SampleStream, Symbolizer};
+use crate::collector::{SampleOpts, consume_ring, deadline, dropped_count, report_lost, ring_bytes, stacktrace_from_id, wait_for_stop};
+use crate::profile::{Histogram, Profile, StackKey, Unit, comm_to_string};
+
+/// 切出时的线程状态
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum BlockState {
+    /// 包括被抢占在内的所有切出
+    #[default]
+    All,
+    /// S 状态：睡眠、锁、等待事件
+    Interruptible,
+    /// D 状态：通常是磁盘 I/O
+    Uninterruptible,
+}
+
+impl BlockState {
+    // 对应内核的 TASK_INTERRUPTIBLE / TASK_UNINTERRUPTIBLE
+    fn mask(self) -> i64 {
+        match self {
+            BlockState::All => 0,
+            BlockState::Interruptible => 0x1,
+            BlockState::Uninterruptible => 0x2,
+        }
+    }
+}
+
+/// 在 eBPF 中执行的过滤条件
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Filter {
+    pub state: BlockState,
+    pub min_block_us: Option<u64>,
+    pub max_block_us: Option<u64>,
+}
+
+fn load(pid: u32, filter: Filter, histogram: bool, ring_buf_size: u32) -> Result<Ebpf> {
+    let min_ns = filter.min_block_us.map_or(0, |us| us.saturating_mul(1000));
+    let max_ns = filter.max_block_us.map_or(u64::MAX, |us| us.saturating_mul(1000));
+    if min_ns > max_ns {
+        bail!("--min-block-us must not exceed --max-block-us");
+    }
 
-pub async fn run(pid: u32, duration: u64) -> Result<()> {
-    let mut bpf = aya::Ebpf::load(aya::include_bytes_aligned!(concat!(
-        env!("OUT_DIR"),
-        "/larkspur-off-cpu"
-    )))?;
+    let mut bpf = EbpfLoader::new()
+        .set_max_entries("EVENTS", ring_bytes(ring_buf_size)?)
+        .set_global("TARGET_PID", &pid, true)
+        .set_global("STATE_MASK", &filter.state.mask(), true)
+        .set_global("MIN_BLOCK_NS", &min_ns, true)
+        .set_global("MAX_BLOCK_NS", &max_ns, true)
+        .set_global("HISTOGRAM", &(histogram as u8), true)
+        .load(aya::include_bytes_aligned!(concat!(
+            env!("OUT_DIR"),
+            "/larkspur-off-cpu"
+        )))?;
 
-    let prog: &mut RawTracePoint =
+    let prog: &mut TracePoint =
         bpf.program_mut("off_cpu_trace").unwrap().try_into()?;
     prog.load()?;
-    prog.attach("sched_switch")?;
+    prog.attach("sched", "sched_switch")?;
+    Ok(bpf)
+}
 
-    let mut events = RingBuf::try_from(bpf.take_map("EVENTS").unwrap())?;
+/// `wakers` 为 true 时同时跟踪 sched_waking，把唤醒者的栈接在阻塞栈之后
+pub async fn run(pid: u32, duration: u64, filter: Filter, wakers: bool, opts: SampleOpts, ring_buf_size: u32) -> Result<Profile> {
+    let mut bpf = load(pid, filter, false, ring_buf_size)?;
+
+    if wakers {
+        let prog: &mut TracePoint =
+            bpf.program_mut("wakeup_trace").unwrap().try_into()?;
+        prog.load()?;
+        prog.attach("sched", "sched_waking")?;
+    }
+
+    let events = RingBuf::try_from(bpf.take_map("EVENTS").unwrap())?;
+    let dropped = PerCpuArray::<_, u64>::try_from(bpf.take_map("DROPPED").unwrap())?;
     let mut kstack_map = StackTraceMap::try_from(bpf.take_map("KSTACK").unwrap())?;
     let mut ustack_map = StackTraceMap::try_from(bpf.take_map("USTACK").unwrap())?;
 
     let k_resolver = kstack::KStackResolver::new()?;
-    let u_resolver = ustack::Resolver::new(pid as Pid)?;
+    let mut u_resolvers = ustack::ResolverCache::with_pid(pid as Pid)?;
 
-    let deadline = Instant::now() + Duration::from_secs(duration);
+    let mut agg = opts.aggregator()?;
 
+    let collected = consume_ring(events, deadline(duration), |record| {
+        let sample: &OffCpuSample = bytemuck::from_bytes(record);
 
-    task::spawn_blocking(move || {
-        while Instant::now() < deadline {
-            if let Some(record) = events.next() {
-                let sample: &OffCpuSample = bytemuck::from_bytes(record.as_ref());
+        let waker = (wakers && sample.waker.pid != 0).then(|| Box::new(StackKey {
+            pid: sample.waker.tgid,
+            tid: opts.tid(sample.waker.pid),
+            comm: comm_to_string(&sample.waker.comm),
+            kaddrs: stacktrace_from_id(&mut kstack_map, sample.waker.kstack_id),
+            uaddrs: stacktrace_from_id(&mut ustack_map, sample.waker.ustack_id),
+            waker: None,
+        }));
+        let comm = comm_to_string(&sample.comm);
+        metrics::off_cpu_ns(sample.tgid, &comm, sample.off_ns);
+        let key = StackKey {
+            pid: sample.tgid,
+            tid: opts.tid(sample.pid),
+            comm,
+            kaddrs: stacktrace_from_id(&mut kstack_map, sample.kstack_id),
+            uaddrs: stacktrace_from_id(&mut ustack_map, sample.ustack_id),
+            waker,
+        };
+        agg.add_at(key, sample.off_ns, sample.ts, sample.off_ns, sample.pid, sample.cpu);
+    }, || {
+        let names: &[&str] = if wakers { &["off_cpu_trace", "wakeup_trace"] } else { &["off_cpu_trace"] };
+        for name in names {
+            let prog: &mut TracePoint = bpf.program_mut(name).unwrap().try_into()?;
+            prog.unload()?;
+        }
+        Ok(())
+    }).await?;
 
-                let kaddrs = stacktrace_from_id(&mut kstack_map, sample.kstack_id);
-                let uaddrs = stacktrace_from_id(&mut ustack_map, sample.ustack_id);
+    report_lost("off-cpu", collected, dropped_count(&dropped));
 
-                let kframes = k_resolver.symbolize_stack(&kaddrs);
-                let uframes = u_resolver.symbolize_stack(&uaddrs);
+    let mut profile = agg.symbolize(&k_resolver, &mut u_resolvers);
+    profile.unit = Unit::Nanoseconds;
+    Ok(profile)
+}
 
-                info!("K frames:");
-                for i in kframes {
-                    info!("{:x}:{}",i.offset, i.to_string_lossy());
-                }
+/// 逐个阻塞事件推送给调用方，不做聚合。`pid` 为 None 时跟踪所有进程；
+/// `symbolize` 为 true 时在后台任务中逐个符号化阻塞栈
+pub fn stream(pid: Option<u32>, filter: Filter, symbolize: bool, ring_buf_size: u32) -> Result<SampleStream> {
+    let mut bpf = load(pid.unwrap_or(0), filter, false, ring_buf_size)?;
 
-                info!("U frames:");
-                for i in uframes {
-                    for a in i {
-                        info!("{:x}:{}",a.offset, a.to_string_lossy());
-                    }
-                }
+    let ring = RingBuf::try_from(bpf.take_map("EVENTS").unwrap())?;
+    let mut kstack_map = StackTraceMap::try_from(bpf.take_map("KSTACK").unwrap())?;
+    let mut ustack_map = StackTraceMap::try_from(bpf.take_map("USTACK").unwrap())?;
+    let symbolizer = symbolize.then(|| Symbolizer::new(pid)).transpose()?;
+
+    Ok(stream::spawn("off-cpu", bpf, ring, symbolizer, move |record| {
+        let sample: &OffCpuSample = bytemuck::from_bytes(record);
+        SampleEvent {
+            kind: SampleKind::OffCpu { off_ns: sample.off_ns },
+            pid: sample.tgid,
+            tid: sample.pid,
+            cpu: sample.cpu,
+            ts: sample.ts,
+            comm: comm_to_string(&sample.comm),
+            kaddrs: stacktrace_from_id(&mut kstack_map, sample.kstack_id),
+            uaddrs: stacktrace_from_id(&mut ustack_map, sample.ustack_id),
+            frames: None,
+        }
+    }))
+}
+
+/// 在内核中按阻塞栈累计阻塞时长的 log2 直方图，结束时一次性读出
+pub async fn histogram(pid: u32, duration: u64, filter: Filter, threads: bool) -> Result<Vec<Histogram>> {
+    // 不经过 ring buffer，沿用默认大小
+    let mut bpf = load(pid, filter, true, 256)?;
+
+    let mut kstack_map = StackTraceMap::try_from(bpf.take_map("KSTACK").unwrap())?;
+    let mut ustack_map = StackTraceMap::try_from(bpf.take_map("USTACK").unwrap())?;
+    let hists = PerCpuHashMap::<_, StackIdent, LatencyHist>::try_from(bpf.take_map("HISTS").unwrap())?;
+
+    let k_resolver = kstack::KStackResolver::new()?;
+    let mut u_resolvers = ustack::ResolverCache::with_pid(pid as Pid)?;
+    wait_for_stop(deadline(duration)).await?;
+
+    let prog: &mut TracePoint = bpf.program_mut("off_cpu_trace").unwrap().try_into()?;
+    prog.unload()?;
+
+    // 内核中的键总是区分线程，不按线程拆分时在这里合并
+    let mut groups: HashMap<StackKey, (u64, Vec<u64>)> = HashMap::new();
+    for entry in hists.iter() {
+        let (ident, per_cpu) = entry?;
+        let comm = comm_to_string(&ident.comm);
+        metrics::off_cpu_ns(ident.tgid, &comm, per_cpu.iter().map(|h| h.total_ns).sum());
+        let key = StackKey {
+            pid: ident.tgid,
+            tid: if threads { ident.pid } else { 0 },
+            comm,
+            kaddrs: stacktrace_from_id(&mut kstack_map, ident.kstack_id),
+            uaddrs: stacktrace_from_id(&mut ustack_map, ident.ustack_id),
+            waker: None,
+        };
+        let (total_ns, slots) = groups.entry(key).or_insert_with(|| (0, vec![0; HIST_SLOTS]));
+        for hist in per_cpu.iter() {
+            *total_ns += hist.total_ns;
+            for (sum, count) in slots.iter_mut().zip(hist.slots) {
+                *sum += count;
             }
         }
-    }).await?;
+    }
 
-    Ok(())
-}
\ No newline at end of file
+    let mut out: Vec<Histogram> = groups
+        .into_iter()
+        .map(|(key, (total_ns, slots))| Histogram {
+            stack: key.symbolize(total_ns, &k_resolver, &mut u_resolvers),
+            slots,
+        })
+        .collect();
+    out.sort_by_key(|h| Reverse(h.stack.weight));
+    Ok(out)
+}
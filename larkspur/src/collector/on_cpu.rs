@@ -1,82 +1,223 @@
-use std::time::{Duration, Instant};
-use aya::
-{programs::
- {
-     PerfEvent,
-     PerfEventScope,
-     PerfTypeId,
-     SamplePolicy,
-     perf_event::perf_sw_ids::PERF_COUNT_SW_CPU_CLOCK},
- util::online_cpus,
- maps::{RingBuf, StackTraceMap},
+use std::collections::HashSet;
+
+use aya::{
+    Ebpf, EbpfLoader,
+    programs::{PerfEvent, SamplePolicy},
+    maps::{MapData, PerCpuArray, PerCpuHashMap, RingBuf, StackTraceMap},
 };
-use log::info;
+use log::{info, warn};
 use proc_maps::Pid;
-use tokio::task;
-use larkspur_common::Sample;
+use larkspur_common::{Sample, StackIdent};
 use crate::symbolize::{kstack, ustack};
-use crate::collector::stacktrace_from_id;
+use crate::metrics;
+use crate::collector::stream::{self, SampleEvent, SampleKind, SampleStream, Symbolizer};
+use crate::collector::{SampleOpts, consume_ring, event::{self, Event}, deadline, dropped_count, report_lost, ring_bytes, stacktrace_from_id, wait_for_stop};
+use crate::profile::{Aggregator, Profile, StackKey, comm_to_string};
 
-pub async fn run(pid: u32, duration: u64, frequency: u64) -> anyhow::Result<()> {
-    let mut bpf = aya::Ebpf::load(aya::include_bytes_aligned!(concat!(
-        env!("OUT_DIR"),
-        "/larkspur-on-cpu"
-    )))?;
+/// `aggregate` 为 true 时在内核中按栈计数、结束时一次性读出，此时没有样本时间，
+/// `opts` 中只有按线程拆分生效；否则逐个样本经 ring buffer 上报
+pub async fn run(pid: u32, duration: u64, event: Event, mut policy: SamplePolicy, aggregate: bool, opts: SampleOpts, ring_buf_size: u32) -> anyhow::Result<Profile> {
+    let mut bpf = load(Some(pid), event, &mut policy, aggregate, ring_buf_size)?;
 
-    let prog: &mut PerfEvent =
-        bpf.program_mut("on_cpu_trace").unwrap().try_into()?;
-    prog.load()?;
+    let mut ustack = StackTraceMap::try_from(bpf.take_map("USTACKS").unwrap())?;
+    let mut kstack = StackTraceMap::try_from(bpf.take_map("STACKS").unwrap())?;
+    let sample = RingBuf::try_from(bpf.take_map("SAMPLES").unwrap())?;
+    let dropped = PerCpuArray::<_, u64>::try_from(bpf.take_map("DROPPED").unwrap())?;
+    let counts = PerCpuHashMap::<_, StackIdent, u64>::try_from(bpf.take_map("COUNTS").unwrap())?;
 
-    let cpus = online_cpus().unwrap();
+    let k_resolver = kstack::KStackResolver::new()?;
+    let mut u_resolvers = ustack::ResolverCache::with_pid(pid as Pid)?;
 
-    for cpu in cpus {
-        let scope = PerfEventScope::OneProcessOneCpu {
-            pid,
-            cpu
-        };
-        prog.attach(
-            PerfTypeId::Software,
-            PERF_COUNT_SW_CPU_CLOCK as u64,
-            scope,
-            SamplePolicy::Frequency(frequency),
-            false,
-        )?;
-    }
+    let mut agg = if aggregate { Aggregator::default() } else { opts.aggregator()? };
+    // 按频率采样时每个样本代表一个采样周期，按事件计数时无法换算成时长
+    let period_ns = match policy {
+        SamplePolicy::Frequency(f) if f > 0 => 1_000_000_000 / f,
+        _ => 0,
+    };
+
+    let mut detach = || {
+        let prog: &mut PerfEvent = bpf.program_mut("on_cpu_trace").unwrap().try_into()?;
+        prog.unload()?;
+        Ok(())
+    };
+
+    let collected = if aggregate {
+        wait_for_stop(deadline(duration)).await?;
+        detach()?;
+
+        let mut collected = 0;
+        for entry in counts.iter() {
+            let (ident, per_cpu) = entry?;
+            let count: u64 = per_cpu.iter().sum();
+            collected += count;
+            let comm = comm_to_string(&ident.comm);
+            metrics::cpu_samples(ident.tgid, &comm, count);
+            let key = StackKey {
+                pid: ident.tgid,
+                tid: opts.tid(ident.pid),
+                comm,
+                kaddrs: stacktrace_from_id(&mut kstack, ident.kstack_id),
+                uaddrs: stacktrace_from_id(&mut ustack, ident.ustack_id),
+                waker: None,
+            };
+            agg.add(key, count);
+        }
+        collected
+    } else {
+        consume_ring(sample, deadline(duration), |record| {
+            let sample: &Sample = bytemuck::from_bytes(record);
+            let comm = comm_to_string(bytemuck::cast_slice(&sample.comm));
+            metrics::cpu_samples(sample.tgid, &comm, 1);
+
+            let key = StackKey {
+                pid: sample.tgid,
+                tid: opts.tid(sample.pid),
+                comm,
+                kaddrs: stacktrace_from_id(&mut kstack, sample.kstack_id),
+                uaddrs: stacktrace_from_id(&mut ustack, sample.ustack_id),
+                waker: None,
+            };
+            agg.add_at(key, 1, sample.ts, period_ns, sample.pid, sample.cpu);
+        }, detach).await?
+    };
+
+    report_lost("on-cpu", collected, dropped_count(&dropped));
+
+    Ok(agg.symbolize(&k_resolver, &mut u_resolvers))
+}
+
+/// 逐个样本推送给调用方，不做聚合。`pid` 为 None 时采样所有进程；
+/// `symbolize` 为 true 时在后台任务中逐个样本符号化，开销较大
+pub fn stream(pid: Option<u32>, event: Event, mut policy: SamplePolicy, symbolize: bool, ring_buf_size: u32) -> anyhow::Result<SampleStream> {
+    let mut bpf = load(pid, event, &mut policy, false, ring_buf_size)?;
 
     let mut ustack = StackTraceMap::try_from(bpf.take_map("USTACKS").unwrap())?;
     let mut kstack = StackTraceMap::try_from(bpf.take_map("STACKS").unwrap())?;
-    let mut sample = RingBuf::try_from(bpf.take_map("SAMPLES").unwrap())?;
+    let ring = RingBuf::try_from(bpf.take_map("SAMPLES").unwrap())?;
+    let symbolizer = symbolize.then(|| Symbolizer::new(pid)).transpose()?;
 
-    let k_resolver = kstack::KStackResolver::new()?;
-    let u_resolver = ustack::Resolver::new(pid as Pid)?;
+    Ok(stream::spawn("on-cpu", bpf, ring, symbolizer, move |record| {
+        let sample: &Sample = bytemuck::from_bytes(record);
+        SampleEvent {
+            kind: SampleKind::OnCpu,
+            pid: sample.tgid,
+            tid: sample.pid,
+            cpu: sample.cpu,
+            ts: sample.ts,
+            comm: comm_to_string(bytemuck::cast_slice(&sample.comm)),
+            kaddrs: stacktrace_from_id(&mut kstack, sample.kstack_id),
+            uaddrs: stacktrace_from_id(&mut ustack, sample.ustack_id),
+            frames: None,
+        }
+    }))
+}
+
+/// 加载并挂载 on-cpu 程序，事件回退时 `policy` 会被改为实际使用的值
+fn load(pid: Option<u32>, event: Event, policy: &mut SamplePolicy, aggregate: bool, ring_buf_size: u32) -> anyhow::Result<Ebpf> {
+    let mut bpf = EbpfLoader::new()
+        .set_max_entries("SAMPLES", ring_bytes(ring_buf_size)?)
+        .set_global("AGGREGATE", &(aggregate as u8), true)
+        .load(aya::include_bytes_aligned!(concat!(
+            env!("OUT_DIR"),
+            "/larkspur-on-cpu"
+        )))?;
+
+    let prog: &mut PerfEvent =
+        bpf.program_mut("on_cpu_trace").unwrap().try_into()?;
+    prog.load()?;
+
+    let event = event::attach(prog, event, pid, policy)?;
+    info!("sampling on {event} ({policy:?})");
+    Ok(bpf)
+}
 
-    let deadline = Instant::now() + Duration::from_secs(duration);
+/// 长期挂载的 on-cpu 采样：在内核中按栈计数，由调用方定期取走。
+/// 符号解析器在多次取走之间复用，只淘汰已退出的进程
+pub struct Continuous {
+    // 持有 Ebpf 才能保持程序挂载
+    _bpf: Ebpf,
+    counts: PerCpuHashMap<MapData, StackIdent, u64>,
+    kstack: StackTraceMap<MapData>,
+    ustack: StackTraceMap<MapData>,
+    dropped: PerCpuArray<MapData, u64>,
+    dropped_seen: u64,
+    k_resolver: kstack::KStackResolver,
+    u_resolvers: ustack::ResolverCache,
+    threads: bool,
+}
 
-    task::spawn_blocking(move || {
-        while Instant::now() < deadline {
-            if let Some(record) = sample.next() {
-                let sample: &Sample = bytemuck::from_bytes(record.as_ref());
+impl Continuous {
+    /// `pid` 为 None 时采样所有进程
+    pub fn start(pid: Option<u32>, event: Event, mut policy: SamplePolicy, threads: bool) -> anyhow::Result<Self> {
+        // 不经过 ring buffer，沿用默认大小
+        let mut bpf = load(pid, event, &mut policy, true, 256)?;
 
-                let kaddrs = stacktrace_from_id(&mut kstack, sample.kstack_id);
-                let uaddrs = stacktrace_from_id(&mut ustack, sample.ustack_id);
+        let u_resolvers = match pid {
+            Some(pid) => ustack::ResolverCache::with_pid(pid as Pid)?,
+            None => ustack::ResolverCache::default(),
+        };
+        Ok(Continuous {
+            counts: PerCpuHashMap::try_from(bpf.take_map("COUNTS").unwrap())?,
+            kstack: StackTraceMap::try_from(bpf.take_map("STACKS").unwrap())?,
+            ustack: StackTraceMap::try_from(bpf.take_map("USTACKS").unwrap())?,
+            dropped: PerCpuArray::try_from(bpf.take_map("DROPPED").unwrap())?,
+            dropped_seen: 0,
+            k_resolver: kstack::KStackResolver::new()?,
+            u_resolvers,
+            threads,
+            _bpf: bpf,
+        })
+    }
 
-                let kframes = k_resolver.symbolize_stack(&kaddrs);
-                let uframes = u_resolver.symbolize_stack(&uaddrs);
+    /// 取走上次以来的计数并符号化。
+    /// 读出与删除之间新增的计数会丢失，相对一个周期可以忽略
+    pub fn take(&mut self) -> anyhow::Result<Profile> {
+        let mut entries = Vec::new();
+        for entry in self.counts.iter() {
+            let (ident, per_cpu) = entry?;
+            entries.push((ident, per_cpu.iter().sum::<u64>()));
+        }
+        for (ident, _) in &entries {
+            let _ = self.counts.remove(ident);
+        }
 
-                info!("K frames:");
-                for i in kframes {
-                    info!("{:x}:{}",i.offset, i.to_string_lossy());
-                }
+        let mut agg = Aggregator::default();
+        let (mut kstack_ids, mut ustack_ids) = (HashSet::new(), HashSet::new());
+        let mut collected = 0;
+        for (ident, count) in entries {
+            collected += count;
+            let comm = comm_to_string(&ident.comm);
+            metrics::cpu_samples(ident.tgid, &comm, count);
+            let key = StackKey {
+                pid: ident.tgid,
+                tid: if self.threads { ident.pid } else { 0 },
+                comm,
+                kaddrs: stacktrace_from_id(&mut self.kstack, ident.kstack_id),
+                uaddrs: stacktrace_from_id(&mut self.ustack, ident.ustack_id),
+                waker: None,
+            };
+            kstack_ids.insert(ident.kstack_id);
+            ustack_ids.insert(ident.ustack_id);
+            agg.add(key, count);
+        }
+        // 栈表不会自动老化，不清理的话长期运行后会被占满
+        for id in kstack_ids.into_iter().filter(|&id| id >= 0) {
+            let _ = self.kstack.remove(&(id as u32));
+        }
+        for id in ustack_ids.into_iter().filter(|&id| id >= 0) {
+            let _ = self.ustack.remove(&(id as u32));
+        }
 
-                info!("U frames:");
-                for i in uframes {
-                    for a in i {
-                        info!("{:x}:{}",a.offset, a.to_string_lossy());
-                    }
-                }
-            }
+        let dropped = dropped_count(&self.dropped);
+        let lost = dropped.saturating_sub(self.dropped_seen);
+        if lost > 0 {
+            warn!("{lost} samples dropped because the counts map is full");
+            self.dropped_seen = dropped;
         }
-    }).await?;
+        metrics::samples("on-cpu", collected, lost);
 
-    Ok(())
-}
\ No newline at end of file
+        let profile = agg.symbolize(&self.k_resolver, &mut self.u_resolvers);
+        self.u_resolvers.retain_live();
+        Ok(profile)
+    }
+}
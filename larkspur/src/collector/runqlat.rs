@@ -0,0 +1,125 @@
+use std::{cmp::Reverse, collections::HashMap, fs, os::unix::fs::MetadataExt, path::Path};
+
+use anyhow::Result;
+use aya::{
+    EbpfLoader,
+    maps::{PerCpuHashMap, StackTraceMap},
+    programs::TracePoint,
+};
+use larkspur_common::{HIST_SLOTS, LatencyHist, RunqKey};
+use proc_maps::Pid;
+use crate::symbolize::{kstack, ustack};
+use crate::collector::{deadline, stacktrace_from_id, wait_for_stop};
+use crate::profile::{Histogram, StackKey, process_comm};
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// 直方图的分组维度
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum GroupBy {
+    #[default]
+    Pid,
+    Cgroup,
+}
+
+/// 统计任务从被唤醒（或被抢占）到真正运行之间的等待时间。
+/// `stacks` 为 true 时再按任务上次切出时的栈细分
+pub async fn run(pid: Option<u32>, duration: u64, group_by: GroupBy, stacks: bool) -> Result<Vec<Histogram>> {
+    let mut bpf = EbpfLoader::new()
+        .set_global("TARGET_PID", &pid.unwrap_or(0), true)
+        .set_global("PER_STACK", &(stacks as u8), true)
+        .load(aya::include_bytes_aligned!(concat!(
+            env!("OUT_DIR"),
+            "/larkspur-runqlat"
+        )))?;
+
+    let wakeup: &mut TracePoint = bpf.program_mut("runq_wakeup").unwrap().try_into()?;
+    wakeup.load()?;
+    wakeup.attach("sched", "sched_wakeup")?;
+    wakeup.attach("sched", "sched_wakeup_new")?;
+
+    let switch: &mut TracePoint = bpf.program_mut("runq_switch").unwrap().try_into()?;
+    switch.load()?;
+    switch.attach("sched", "sched_switch")?;
+
+    let mut kstack_map = StackTraceMap::try_from(bpf.take_map("KSTACK").unwrap())?;
+    let mut ustack_map = StackTraceMap::try_from(bpf.take_map("USTACK").unwrap())?;
+    let hists = PerCpuHashMap::<_, RunqKey, LatencyHist>::try_from(bpf.take_map("HISTS").unwrap())?;
+
+    let k_resolver = kstack::KStackResolver::new()?;
+    let mut u_resolvers = match pid {
+        Some(pid) => ustack::ResolverCache::with_pid(pid as Pid)?,
+        None => ustack::ResolverCache::default(),
+    };
+
+    wait_for_stop(deadline(duration)).await?;
+
+    for name in ["runq_wakeup", "runq_switch"] {
+        let prog: &mut TracePoint = bpf.program_mut(name).unwrap().try_into()?;
+        prog.unload()?;
+    }
+
+    let cgroups = match group_by {
+        GroupBy::Cgroup => cgroup_paths(),
+        GroupBy::Pid => HashMap::new(),
+    };
+
+    let mut groups: HashMap<StackKey, (u64, Vec<u64>)> = HashMap::new();
+    for entry in hists.iter() {
+        let (key, per_cpu) = entry?;
+        let comm = match group_by {
+            GroupBy::Pid if key.tgid == 0 => "[unknown]".to_string(),
+            GroupBy::Pid => format!("{} (pid {})", process_comm(key.tgid), key.tgid),
+            GroupBy::Cgroup => cgroups
+                .get(&key.cgroup_id)
+                .cloned()
+                .unwrap_or_else(|| format!("[cgroup {}]", key.cgroup_id)),
+        };
+        let stack = StackKey {
+            // 按 cgroup 分组且不看栈时，不同进程合并到一起
+            pid: if stacks || group_by == GroupBy::Pid { key.tgid } else { 0 },
+            tid: 0,
+            comm,
+            kaddrs: stacktrace_from_id(&mut kstack_map, key.kstack_id),
+            uaddrs: stacktrace_from_id(&mut ustack_map, key.ustack_id),
+            waker: None,
+        };
+
+        let (total_ns, slots) = groups.entry(stack).or_insert_with(|| (0, vec![0; HIST_SLOTS]));
+        for hist in per_cpu.iter() {
+            *total_ns += hist.total_ns;
+            for (sum, count) in slots.iter_mut().zip(hist.slots) {
+                *sum += count;
+            }
+        }
+    }
+
+    let mut out: Vec<Histogram> = groups
+        .into_iter()
+        .map(|(key, (total_ns, slots))| Histogram {
+            stack: key.symbolize(total_ns, &k_resolver, &mut u_resolvers),
+            slots,
+        })
+        .collect();
+    out.sort_by_key(|h| Reverse(h.stack.weight));
+    Ok(out)
+}
+
+/// cgroup v2 中 cgroup id 即目录的 inode 号
+fn cgroup_paths() -> HashMap<u64, String> {
+    let mut out = HashMap::new();
+    let mut dirs = vec![Path::new(CGROUP_ROOT).to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        if let Ok(meta) = fs::metadata(&dir) {
+            let rel = dir.strip_prefix(CGROUP_ROOT).unwrap_or(&dir);
+            out.insert(meta.ino(), format!("/{}", rel.display()));
+        }
+        let Ok(entries) = fs::read_dir(&dir) else { continue };
+        for entry in entries.flatten() {
+            if entry.file_type().is_ok_and(|t| t.is_dir()) {
+                dirs.push(entry.path());
+            }
+        }
+    }
+    out
+}
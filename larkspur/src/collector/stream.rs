@@ -0,0 +1,174 @@
+use std::{
+    pin::Pin,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use aya::{Ebpf, maps::{MapData, RingBuf}};
+use futures_core::Stream;
+use proc_maps::Pid;
+use tokio::sync::mpsc;
+
+use crate::collector::consume_ring_until;
+use crate::metrics;
+use crate::profile::{Frame, FrameKind, StackKey};
+use crate::symbolize::{kstack::KStackResolver, ustack::ResolverCache};
+
+/// 消费者跟不上时最多缓冲的样本数，超出的样本被丢弃并计入 `dropped`
+const CHANNEL_CAPACITY: usize = 4096;
+
+/// 全系统采样时多久淘汰一次已退出进程的符号解析器
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// 解码后的单个样本
+#[derive(Clone, Debug)]
+pub struct SampleEvent {
+    pub kind: SampleKind,
+    /// 进程 id
+    pub pid: u32,
+    /// 线程 id
+    pub tid: u32,
+    /// 采样时所在的 CPU；off-cpu 为切出时所在的 CPU
+    pub cpu: u32,
+    /// CLOCK_MONOTONIC 纳秒；off-cpu 为切出时间
+    pub ts: u64,
+    pub comm: String,
+    /// 内核栈地址，最内层在前
+    pub kaddrs: Vec<u64>,
+    /// 用户栈地址，最内层在前
+    pub uaddrs: Vec<u64>,
+    /// 开启符号化时的用户栈和内核栈帧，从根到叶
+    pub frames: Option<Vec<Frame>>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SampleKind {
+    OnCpu,
+    /// 一次阻塞及其时长
+    OffCpu { off_ns: u64 },
+}
+
+/// 逐个样本的异步流，由 `on_cpu::stream` / `off_cpu::stream` 创建。
+/// 后台任务读取 ring buffer，drop 时停止并卸载 eBPF 程序
+pub struct SampleStream {
+    rx: mpsc::Receiver<Result<SampleEvent>>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl SampleStream {
+    /// 下一个样本；采集出错时先返回错误再结束
+    pub async fn next_sample(&mut self) -> Option<Result<SampleEvent>> {
+        self.rx.recv().await
+    }
+
+    /// 对每个样本调用 `f`，直到流结束或出错
+    pub async fn for_each(mut self, mut f: impl FnMut(SampleEvent)) -> Result<()> {
+        while let Some(event) = self.next_sample().await {
+            f(event?);
+        }
+        Ok(())
+    }
+
+    /// 因消费者处理不及时而丢弃的样本数
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl Stream for SampleStream {
+    type Item = Result<SampleEvent>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.rx.poll_recv(cx)
+    }
+}
+
+/// 在后台任务中符号化样本
+pub(crate) struct Symbolizer {
+    k_resolver: KStackResolver,
+    u_resolvers: ResolverCache,
+    pruned: Instant,
+}
+
+impl Symbolizer {
+    pub(crate) fn new(pid: Option<u32>) -> Result<Self> {
+        Ok(Symbolizer {
+            k_resolver: KStackResolver::new()?,
+            u_resolvers: match pid {
+                Some(pid) => ResolverCache::with_pid(pid as Pid)?,
+                None => ResolverCache::default(),
+            },
+            pruned: Instant::now(),
+        })
+    }
+
+    fn frames(&mut self, event: &SampleEvent) -> Vec<Frame> {
+        if self.pruned.elapsed() >= PRUNE_INTERVAL {
+            self.u_resolvers.retain_live();
+            self.pruned = Instant::now();
+        }
+        let key = StackKey {
+            pid: event.pid,
+            tid: 0,
+            comm: String::new(),
+            kaddrs: event.kaddrs.clone(),
+            uaddrs: event.uaddrs.clone(),
+            waker: None,
+        };
+        key.symbolize(0, &self.k_resolver, &mut self.u_resolvers)
+            .symbols()
+            .into_iter()
+            .filter(|f| matches!(f.kind, FrameKind::User | FrameKind::Kernel))
+            .collect()
+    }
+}
+
+/// 启动后台任务：`decode` 把 ring buffer 记录转成样本，接收端 drop 后停止
+pub(crate) fn spawn<F>(
+    collector: &'static str,
+    bpf: Ebpf,
+    ring: RingBuf<MapData>,
+    mut symbolizer: Option<Symbolizer>,
+    mut decode: F,
+) -> SampleStream
+where
+    F: FnMut(&[u8]) -> SampleEvent + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+    let dropped = Arc::new(AtomicU64::new(0));
+    let counter = dropped.clone();
+
+    tokio::spawn(async move {
+        // 持有 Ebpf 才能保持程序挂载，任务结束时随之卸载
+        let _bpf = bpf;
+        let closed = tx.clone();
+        let stop = async move {
+            closed.closed().await;
+            Ok(())
+        };
+        let result = consume_ring_until(ring, stop, |record| {
+            let mut event = decode(record);
+            if let Some(symbolizer) = &mut symbolizer {
+                event.frames = Some(symbolizer.frames(&event));
+            }
+            if tx.try_send(Ok(event)).is_err() {
+                counter.fetch_add(1, Ordering::Relaxed);
+            }
+        }, || Ok(()))
+        .await;
+
+        match result {
+            Ok(collected) => metrics::samples(collector, collected, counter.load(Ordering::Relaxed)),
+            Err(e) => {
+                let _ = tx.send(Err(e)).await;
+            }
+        }
+    });
+
+    SampleStream { rx, dropped }
+}
@@ -0,0 +1,170 @@
+use std::{fmt, str::FromStr};
+
+use anyhow::{Result, anyhow};
+use aya::{
+    EbpfLoader,
+    maps::{PerCpuArray, RingBuf, StackTraceMap},
+    programs::{KProbe, TracePoint, UProbe},
+};
+use larkspur_common::Sample;
+use log::info;
+use crate::symbolize::{kstack, ustack};
+use crate::collector::{SampleOpts, consume_ring, deadline, dropped_count, report_lost, ring_bytes, stacktrace_from_id};
+use crate::profile::{Profile, StackKey, comm_to_string};
+
+/// 触发栈采集的探测点
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Probe {
+    /// `category:name`，如 `syscalls:sys_enter_write`
+    Tracepoint { category: String, name: String },
+    /// 内核函数名，如 `tcp_retransmit_skb`
+    Kprobe { function: String },
+    /// `/path/to/binary:symbol`，如 `/usr/lib/libc.so.6:malloc`；
+    /// 也可以是相对路径（`./app:main`）或按动态链接器缓存查找的库名（`libc.so.6:malloc`）
+    Uprobe { path: String, symbol: String },
+}
+
+impl Probe {
+    fn program(&self) -> &'static str {
+        match self {
+            Probe::Tracepoint { .. } => "trace_tracepoint",
+            Probe::Kprobe { .. } => "trace_kprobe",
+            Probe::Uprobe { .. } => "trace_uprobe",
+        }
+    }
+}
+
+impl FromStr for Probe {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        // tracepoint 的类别不含 `/` 和 `.so`，带这些的是文件路径或库名（如 `libc.so.6`）
+        let target = s.rsplit_once(':').map_or(s, |(path, _)| path);
+        if target.contains('/') || target.contains(".so") {
+            let (path, symbol) = s
+                .rsplit_once(':')
+                .filter(|(_, symbol)| !symbol.is_empty())
+                .ok_or_else(|| anyhow!("uprobe must look like /path/to/binary:symbol, got `{s}`"))?;
+            return Ok(Probe::Uprobe { path: path.to_string(), symbol: symbol.to_string() });
+        }
+        Ok(match s.split_once(':') {
+            Some((category, name)) => Probe::Tracepoint { category: category.to_string(), name: name.to_string() },
+            None => Probe::Kprobe { function: s.to_string() },
+        })
+    }
+}
+
+impl fmt::Display for Probe {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Probe::Tracepoint { category, name } => write!(f, "tracepoint {category}:{name}"),
+            Probe::Kprobe { function } => write!(f, "kprobe {function}"),
+            Probe::Uprobe { path, symbol } => write!(f, "uprobe {path}:{symbol}"),
+        }
+    }
+}
+
+/// 每次 `probe` 触发时采集内核栈和用户栈，`pid` 为 None 时跟踪所有进程
+pub async fn run(probe: Probe, pid: Option<u32>, duration: u64, opts: SampleOpts, ring_buf_size: u32) -> Result<Profile> {
+    let mut bpf = EbpfLoader::new()
+        .set_max_entries("SAMPLES", ring_bytes(ring_buf_size)?)
+        .set_global("TARGET_PID", &pid.unwrap_or(0), true)
+        .load(aya::include_bytes_aligned!(concat!(
+            env!("OUT_DIR"),
+            "/larkspur-trace"
+        )))?;
+
+    let name = probe.program();
+    match &probe {
+        Probe::Tracepoint { category, name: event } => {
+            let prog: &mut TracePoint = bpf.program_mut(name).unwrap().try_into()?;
+            prog.load()?;
+            prog.attach(category, event)?;
+        }
+        Probe::Kprobe { function } => {
+            let prog: &mut KProbe = bpf.program_mut(name).unwrap().try_into()?;
+            prog.load()?;
+            prog.attach(function, 0)?;
+        }
+        Probe::Uprobe { path, symbol } => {
+            let prog: &mut UProbe = bpf.program_mut(name).unwrap().try_into()?;
+            prog.load()?;
+            prog.attach(Some(symbol), 0, path, pid.map(|p| p as i32))?;
+        }
+    }
+    info!("tracing {probe}");
+
+    let mut ustack = StackTraceMap::try_from(bpf.take_map("USTACKS").unwrap())?;
+    let mut kstack = StackTraceMap::try_from(bpf.take_map("STACKS").unwrap())?;
+    let samples = RingBuf::try_from(bpf.take_map("SAMPLES").unwrap())?;
+    let dropped = PerCpuArray::<_, u64>::try_from(bpf.take_map("DROPPED").unwrap())?;
+
+    let k_resolver = kstack::KStackResolver::new()?;
+    let mut u_resolvers = match pid {
+        Some(pid) => ustack::ResolverCache::with_pid(pid as proc_maps::Pid)?,
+        None => ustack::ResolverCache::default(),
+    };
+
+    let mut agg = opts.aggregator()?;
+
+    let collected = consume_ring(samples, deadline(duration), |record| {
+        let sample: &Sample = bytemuck::from_bytes(record);
+
+        let key = StackKey {
+            pid: sample.tgid,
+            tid: opts.tid(sample.pid),
+            comm: comm_to_string(bytemuck::cast_slice(&sample.comm)),
+            kaddrs: stacktrace_from_id(&mut kstack, sample.kstack_id),
+            uaddrs: stacktrace_from_id(&mut ustack, sample.ustack_id),
+            waker: None,
+        };
+        agg.add_at(key, 1, sample.ts, 0, sample.pid, sample.cpu);
+    }, || {
+        let prog = bpf.program_mut(name).unwrap();
+        match probe {
+            Probe::Tracepoint { .. } => <&mut TracePoint>::try_from(prog)?.unload()?,
+            Probe::Kprobe { .. } => <&mut KProbe>::try_from(prog)?.unload()?,
+            Probe::Uprobe { .. } => <&mut UProbe>::try_from(prog)?.unload()?,
+        }
+        Ok(())
+    }).await?;
+
+    report_lost("trace", collected, dropped_count(&dropped));
+
+    Ok(agg.symbolize(&k_resolver, &mut u_resolvers))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn uprobe(path: &str, symbol: &str) -> Probe {
+        Probe::Uprobe { path: path.into(), symbol: symbol.into() }
+    }
+
+    #[test]
+    fn probe_forms() {
+        assert_eq!(
+            "syscalls:sys_enter_write".parse::<Probe>().unwrap(),
+            Probe::Tracepoint { category: "syscalls".into(), name: "sys_enter_write".into() }
+        );
+        assert_eq!(
+            "tcp_retransmit_skb".parse::<Probe>().unwrap(),
+            Probe::Kprobe { function: "tcp_retransmit_skb".into() }
+        );
+        assert_eq!("/usr/lib/libc.so.6:malloc".parse::<Probe>().unwrap(), uprobe("/usr/lib/libc.so.6", "malloc"));
+    }
+
+    #[test]
+    fn relative_uprobes() {
+        assert_eq!("./app:main".parse::<Probe>().unwrap(), uprobe("./app", "main"));
+        assert_eq!("bin/app:main".parse::<Probe>().unwrap(), uprobe("bin/app", "main"));
+        assert_eq!("libc.so.6:malloc".parse::<Probe>().unwrap(), uprobe("libc.so.6", "malloc"));
+    }
+
+    #[test]
+    fn uprobe_needs_symbol() {
+        assert!("/usr/bin/app".parse::<Probe>().is_err());
+        assert!("./app:".parse::<Probe>().is_err());
+    }
+}
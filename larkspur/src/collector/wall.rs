@@ -0,0 +1,25 @@
+use anyhow::{Result, bail};
+use aya::programs::SamplePolicy;
+use crate::collector::{SampleOpts, event::Event, off_cpu, on_cpu};
+use crate::profile::{Profile, Unit};
+
+/// 同时运行 on-cpu 与 off-cpu 采集，把两者都换算成纳秒后合并成一个 profile，
+/// 叶端分别标记 `[on-cpu]` / `[off-cpu]`
+pub async fn run(pid: u32, duration: u64, frequency: u64, threads: bool, ring_buf_size: u32) -> Result<Profile> {
+    if frequency == 0 {
+        bail!("frequency must be greater than zero");
+    }
+
+    let opts = SampleOpts { threads, ..Default::default() };
+    let (mut on, mut off) = tokio::try_join!(
+        on_cpu::run(pid, duration, Event::CpuClock, SamplePolicy::Frequency(frequency), true, opts, ring_buf_size),
+        off_cpu::run(pid, duration, off_cpu::Filter::default(), false, opts, ring_buf_size),
+    )?;
+
+    // 每个 on-cpu 样本代表一个采样周期的 CPU 时间
+    on.scale(1_000_000_000 / frequency, Unit::Nanoseconds);
+    on.label("[on-cpu]");
+    off.label("[off-cpu]");
+
+    Ok(on.merge(off))
+}
@@ -0,0 +1,263 @@
+use std::{ffi::OsString, fs, path::Path};
+
+use anyhow::{Context, Result, bail};
+use clap::{
+    Arg, ArgAction, ArgMatches, Command, CommandFactory, Id,
+    parser::ValueSource,
+};
+use toml::{Table, Value};
+
+use crate::Opt;
+
+/// 把 `--config` 指定的 TOML 文件展开成命令行参数，与实际参数合并。
+///
+/// 顶层的键是全局选项，`[on-cpu]`、`[daemon]` 等表对应同名子命令的选项，
+/// 键名即长选项名（`max_files` 与 `max-files` 等价）。命令行上给出的选项，
+/// 以及与之互斥的选项，会忽略配置中的值；可重复的选项（如 `label`）两边的值都保留。
+/// 配置中打开的开关可以用 `--no-<开关名>` 关闭。位置参数（如 trace 的探测点）只能在命令行上给出
+pub fn merge_args(args: Vec<OsString>) -> Result<Vec<OsString>> {
+    let mut cmd = Opt::command();
+    cmd.build();
+    let (args, negated) = strip_negations(&cmd, args);
+    let Some(path) = config_path(&args) else {
+        return Ok(args);
+    };
+    let table = load(Path::new(&path))?;
+    merge(&cmd, args, &negated, &table, &path.to_string_lossy())
+}
+
+fn merge(cmd: &Command, args: Vec<OsString>, negated: &[Id], table: &Table, origin: &str) -> Result<Vec<OsString>> {
+    let sub_pos = subcommand_pos(cmd, &args);
+    let sub = sub_pos.and_then(|i| cmd.find_subcommand(&args[i]));
+
+    // 先宽松地解析一遍，找出命令行上给出的选项
+    let matches = cmd.clone().ignore_errors(true).try_get_matches_from(&args).ok();
+    let sub_matches = sub.and_then(|s| matches.as_ref()?.subcommand_matches(s.get_name()));
+    let given_in = |c: &Command, m: Option<&ArgMatches>| -> Vec<Id> {
+        c.get_arguments()
+            .filter(|a| m.is_some_and(|m| m.value_source(a.get_id().as_str()) == Some(ValueSource::CommandLine)))
+            .map(|a| a.get_id().clone())
+            .collect()
+    };
+    let mut given = given_in(cmd, matches.as_ref());
+    if let Some(sub) = sub {
+        given.extend(given_in(sub, sub_matches));
+    }
+    given.extend(negated.iter().cloned());
+
+    let mut global = Vec::new();
+    let mut section = Vec::new();
+    for (key, value) in table {
+        match value {
+            Value::Table(t) => {
+                let Some(c) = cmd.find_subcommand(key) else {
+                    bail!("{origin}: unknown section [{key}]");
+                };
+                if sub.is_some_and(|s| s.get_name() == c.get_name()) {
+                    for (k, v) in t {
+                        let ctx = || format!("{origin}: [{key}] {k}");
+                        if !overridden(c, k, &given).with_context(ctx)? {
+                            push(&mut section, k, v).with_context(ctx)?;
+                        }
+                    }
+                }
+            }
+            // 避免配置文件再指定配置文件
+            _ if key == "config" => {}
+            v => {
+                let ctx = || format!("{origin}: {key}");
+                if !overridden(cmd, key, &given).with_context(ctx)? {
+                    push(&mut global, key, v).with_context(ctx)?;
+                }
+            }
+        }
+    }
+
+    let mut merged = Vec::with_capacity(args.len() + global.len() + section.len());
+    let mut rest = args.into_iter();
+    merged.extend(rest.next());
+    merged.extend(global);
+    if let Some(i) = sub_pos {
+        merged.extend(rest.by_ref().take(i));
+        merged.extend(section);
+    }
+    merged.extend(rest);
+    Ok(merged)
+}
+
+/// 子命令的位置：第一个等于子命令名的参数
+fn subcommand_pos(cmd: &Command, args: &[OsString]) -> Option<usize> {
+    args.iter().position(|a| a.to_str().is_some_and(|a| cmd.find_subcommand(a).is_some()))
+}
+
+fn find_long<'a>(cmd: &'a Command, key: &str) -> Option<&'a Arg> {
+    let long = key.replace('_', "-");
+    cmd.get_arguments().find(|a| a.get_long() == Some(long.as_str()))
+}
+
+/// 命令行上给出了该选项（可重复的选项除外），或给出了与之互斥的选项
+fn overridden(cmd: &Command, key: &str, given: &[Id]) -> Result<bool> {
+    let Some(arg) = find_long(cmd, key) else {
+        bail!("unknown option");
+    };
+    if given.contains(arg.get_id()) && !matches!(arg.get_action(), ArgAction::Append) {
+        return Ok(true);
+    }
+    let conflicts = |a: &Arg, b: &Arg| cmd.get_arg_conflicts_with(a).iter().any(|c| c.get_id() == b.get_id());
+    Ok(given
+        .iter()
+        .filter_map(|id| cmd.get_arguments().find(|a| a.get_id() == id))
+        .any(|other| conflicts(arg, other) || conflicts(other, arg)))
+}
+
+/// 去掉 `--no-<开关名>`，返回剩下的参数和被关闭的开关
+fn strip_negations(cmd: &Command, args: Vec<OsString>) -> (Vec<OsString>, Vec<Id>) {
+    let sub = subcommand_pos(cmd, &args).and_then(|i| cmd.find_subcommand(&args[i]));
+    let mut negated = Vec::new();
+    let mut rest = Vec::with_capacity(args.len());
+    let mut iter = args.into_iter();
+    for arg in iter.by_ref() {
+        if arg == "--" {
+            rest.push(arg);
+            break;
+        }
+        let flag = arg.to_str().and_then(|a| a.strip_prefix("--no-")).and_then(|name| {
+            let c = sub.unwrap_or(cmd);
+            // `--no-normalize` 这样本身就是选项的保持原样
+            if find_long(c, &format!("no-{name}")).is_some() {
+                return None;
+            }
+            find_long(c, name).filter(|a| matches!(a.get_action(), ArgAction::SetTrue))
+        });
+        match flag {
+            Some(flag) => negated.push(flag.get_id().clone()),
+            None => rest.push(arg),
+        }
+    }
+    rest.extend(iter);
+    (rest, negated)
+}
+
+/// 在解析之前找出 `--config <path>` 或 `--config=<path>`
+fn config_path(args: &[OsString]) -> Option<OsString> {
+    let mut iter = args.iter().skip(1);
+    while let Some(arg) = iter.next() {
+        let arg = arg.to_str()?;
+        if arg == "--" {
+            return None;
+        }
+        if arg == "--config" {
+            return iter.next().cloned();
+        }
+        if let Some(path) = arg.strip_prefix("--config=") {
+            return Some(path.into());
+        }
+    }
+    None
+}
+
+fn load(path: &Path) -> Result<Table> {
+    let text = fs::read_to_string(path).with_context(|| format!("failed to read {}", path.display()))?;
+    text.parse().with_context(|| format!("failed to parse {}", path.display()))
+}
+
+/// `true` 展开成开关，`false` 省略，数组展开成重复的选项
+fn push(out: &mut Vec<OsString>, key: &str, value: &Value) -> Result<()> {
+    let flag = format!("--{}", key.replace('_', "-"));
+    match value {
+        Value::Boolean(true) => out.push(flag.into()),
+        Value::Boolean(false) => {}
+        Value::Array(values) => {
+            for v in values {
+                if matches!(v, Value::Array(_) | Value::Table(_) | Value::Boolean(_)) {
+                    bail!("arrays may only contain strings and numbers");
+                }
+                push(out, key, v)?;
+            }
+        }
+        Value::Table(_) => bail!("nested tables are not supported"),
+        Value::String(s) => out.extend([flag.into(), s.into()]),
+        v => out.extend([flag.into(), v.to_string().into()]),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    fn merged(config: &str, args: &[&str]) -> Result<Vec<String>> {
+        let mut cmd = Opt::command();
+        cmd.build();
+        let args = args.iter().map(OsString::from).collect();
+        let (args, negated) = strip_negations(&cmd, args);
+        let table = config.parse()?;
+        let merged = merge(&cmd, args, &negated, &table, "test.toml")?;
+        Opt::try_parse_from(&merged)?;
+        Ok(merged.into_iter().map(|a| a.into_string().unwrap()).collect())
+    }
+
+    #[test]
+    fn section_after_subcommand() {
+        let args = merged("debug_dir = \"/dbg\"\n[on-cpu]\npid = 1\nthreads = true\n", &["larkspur", "on-cpu"]).unwrap();
+        assert_eq!(args, ["larkspur", "--debug-dir", "/dbg", "on-cpu", "--pid", "1", "--threads"]);
+    }
+
+    #[test]
+    fn other_sections_ignored() {
+        let args = merged("[daemon]\ninterval = 5\n", &["larkspur", "on-cpu", "-p", "1"]).unwrap();
+        assert_eq!(args, ["larkspur", "on-cpu", "-p", "1"]);
+    }
+
+    #[test]
+    fn command_line_wins() {
+        let args = merged("[on-cpu]\npid = 1\nduration = 5\n", &["larkspur", "on-cpu", "-d", "10"]).unwrap();
+        assert_eq!(args, ["larkspur", "on-cpu", "--pid", "1", "-d", "10"]);
+    }
+
+    #[test]
+    fn conflicting_keys_dropped() {
+        let args = merged("[on-cpu]\npid = 1\nfrequency = 99\n", &["larkspur", "on-cpu", "--period", "1000"]).unwrap();
+        assert_eq!(args, ["larkspur", "on-cpu", "--pid", "1", "--period", "1000"]);
+
+        let args = merged("[on-cpu]\npid = 1\nperiod = 1000\n", &["larkspur", "on-cpu", "-f", "49"]).unwrap();
+        assert_eq!(args, ["larkspur", "on-cpu", "--pid", "1", "-f", "49"]);
+
+        let args = merged("[on-cpu]\npid = 1\nformat = \"pprof\"\n", &["larkspur", "on-cpu", "--report", "tree"]).unwrap();
+        assert_eq!(args, ["larkspur", "on-cpu", "--pid", "1", "--report", "tree"]);
+    }
+
+    #[test]
+    fn negated_switch() {
+        let args = merged("[on-cpu]\npid = 1\nthreads = true\n", &["larkspur", "on-cpu", "--no-threads"]).unwrap();
+        assert_eq!(args, ["larkspur", "on-cpu", "--pid", "1"]);
+    }
+
+    #[test]
+    fn repeated_values_kept() {
+        let config = "[daemon]\nlabel = [\"env=prod\"]\nupload_url = \"http://localhost:4040\"\n";
+        let args = merged(config, &["larkspur", "daemon", "-p", "1", "--label", "zone=a"]).unwrap();
+        assert_eq!(
+            args,
+            ["larkspur", "daemon", "--label", "env=prod", "--upload-url", "http://localhost:4040", "-p", "1", "--label", "zone=a"]
+        );
+    }
+
+    #[test]
+    fn unknown_keys_rejected() {
+        let err = merged("[bogus]\nx = 1\n", &["larkspur", "on-cpu", "-p", "1"]).unwrap_err();
+        assert!(err.to_string().contains("unknown section [bogus]"));
+        let err = merged("[on-cpu]\nbogus = 1\n", &["larkspur", "on-cpu", "-p", "1"]).unwrap_err();
+        assert!(err.to_string().contains("[on-cpu] bogus"));
+    }
+
+    #[test]
+    fn config_path_forms() {
+        let args = |a: &[&str]| a.iter().map(OsString::from).collect::<Vec<_>>();
+        assert_eq!(config_path(&args(&["larkspur", "--config", "a.toml", "on-cpu"])), Some("a.toml".into()));
+        assert_eq!(config_path(&args(&["larkspur", "on-cpu", "--config=b.toml"])), Some("b.toml".into()));
+        assert_eq!(config_path(&args(&["larkspur", "trace", "--", "--config", "c"])), None);
+    }
+}
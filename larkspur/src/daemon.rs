@@ -0,0 +1,356 @@
+use std::{
+    fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result, bail};
+use aya::programs::SamplePolicy;
+use log::{info, warn};
+use tokio::{net::TcpListener, sync::mpsc, task::JoinHandle, time};
+
+use crate::collector::{event::Event, on_cpu::Continuous, shutdown_signal};
+use crate::output::{self, Format};
+use crate::profile::Profile;
+use crate::server;
+use crate::upload::{Uploader, Window};
+
+const FILE_PREFIX: &str = "larkspur-";
+/// 等待上传的 profile 个数
+const UPLOAD_QUEUE: usize = 4;
+/// 退出时等待未完成上传的时长
+const UPLOAD_GRACE: Duration = Duration::from_secs(10);
+
+/// 守护进程配置
+pub struct Config {
+    /// None 表示所有进程
+    pub pid: Option<u32>,
+    pub frequency: u64,
+    pub threads: bool,
+    /// 每隔多久写出一个 profile
+    pub interval: Duration,
+    /// 本地输出目录，只上传时可以不设
+    pub dir: Option<PathBuf>,
+    pub format: Format,
+    /// 最多保留的文件数，0 表示不限
+    pub max_files: usize,
+    /// 超过该时长的文件被删除
+    pub max_age: Option<Duration>,
+    pub upload: Option<Uploader>,
+    /// 提供 Prometheus `/metrics` 的地址
+    pub metrics_listen: Option<SocketAddr>,
+}
+
+/// 持续采样直到收到 SIGINT/SIGTERM，每个周期在 `dir` 下写出一个带时间戳的 profile
+/// 并/或上传，退出前处理最后一个不完整的周期
+pub async fn run(mut config: Config) -> Result<()> {
+    if config.frequency == 0 || config.interval.is_zero() {
+        bail!("frequency and interval must be greater than zero");
+    }
+    if config.dir.is_none() && config.upload.is_none() {
+        bail!("either an output directory or an upload URL is required");
+    }
+    if let Some(dir) = &config.dir {
+        fs::create_dir_all(dir).with_context(|| format!("failed to create {}", dir.display()))?;
+    }
+    if let Some(addr) = config.metrics_listen {
+        let listener = TcpListener::bind(addr).await.with_context(|| format!("failed to listen on {addr}"))?;
+        info!("serving metrics on http://{}/metrics", listener.local_addr()?);
+        let server = server::Config { listen: addr, max_seconds: 0, profiling: false };
+        tokio::spawn(async move {
+            if let Err(e) = server::serve(listener, server).await {
+                warn!("metrics server failed: {e:#}");
+            }
+        });
+    }
+
+    let mut session = Continuous::start(
+        config.pid,
+        Event::CpuClock,
+        SamplePolicy::Frequency(config.frequency),
+        config.threads,
+    )?;
+    if let Some(dir) = &config.dir {
+        info!("writing a profile to {} every {:?}", dir.display(), config.interval);
+    }
+    if let Some(upload) = &config.upload {
+        info!("uploading a profile to {} every {:?}", upload.url, config.interval);
+    }
+    let mut uploads = config.upload.take().map(Uploads::spawn);
+
+    let mut ticker = time::interval_at(time::Instant::now() + config.interval, config.interval);
+    ticker.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+    let stop = shutdown_signal();
+    tokio::pin!(stop);
+
+    let mut started = SystemTime::now();
+    loop {
+        let last = tokio::select! {
+            _ = ticker.tick() => false,
+            r = &mut stop => {
+                r?;
+                info!("interrupted, writing the final profile");
+                true
+            }
+        };
+
+        // 单个周期出错只跳过该周期，守护进程继续运行
+        let profile = match session.take() {
+            Ok(profile) => profile,
+            Err(e) => {
+                warn!("failed to read the profile, skipping this interval: {e:#}");
+                if last {
+                    break;
+                }
+                continue;
+            }
+        };
+        let window = Window { start: started, end: SystemTime::now(), sample_rate: config.frequency };
+        started = window.end;
+
+        if let Some(dir) = &config.dir {
+            let path = dir.join(file_name(window.start, config.format));
+            match write_file(&path, &profile, config.format) {
+                Ok(()) => info!("wrote {} ({} stacks)", path.display(), profile.stacks.len()),
+                Err(e) => warn!("failed to write {}, skipping this interval: {e:#}", path.display()),
+            }
+
+            if let Err(e) = prune(dir, config.max_files, config.max_age) {
+                warn!("failed to apply retention in {}: {e}", dir.display());
+            }
+        }
+        if let Some(uploads) = &mut uploads {
+            uploads.push(profile, window);
+        }
+        if last {
+            break;
+        }
+    }
+    if let Some(uploads) = uploads {
+        uploads.finish().await;
+    }
+    Ok(())
+}
+
+/// 先写临时文件再改名，读取方不会看到写了一半的文件；失败时删除临时文件
+fn write_file(path: &Path, profile: &Profile, format: Format) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    let result = (|| -> Result<()> {
+        let mut w = output::open(Some(&tmp))?;
+        output::write(profile, format, &mut w)?;
+        drop(w);
+        fs::rename(&tmp, path)?;
+        Ok(())
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    result
+}
+
+/// 在后台任务中按顺序上传，重试期间不阻塞采集和退出信号
+struct Uploads {
+    uploader: Arc<Uploader>,
+    tx: mpsc::Sender<(Profile, Window)>,
+    worker: JoinHandle<()>,
+}
+
+impl Uploads {
+    fn spawn(uploader: Uploader) -> Self {
+        let uploader = Arc::new(uploader);
+        let (tx, mut rx) = mpsc::channel::<(Profile, Window)>(UPLOAD_QUEUE);
+        let worker = tokio::spawn({
+            let uploader = uploader.clone();
+            async move {
+                while let Some((profile, window)) = rx.recv().await {
+                    // 上传失败不影响采集，请求已进入 spool 或被丢弃
+                    if let Err(e) = uploader.upload(&profile, &window).await {
+                        warn!("{e:#}");
+                    }
+                }
+            }
+        });
+        Uploads { uploader, tx, worker }
+    }
+
+    /// 排队上传；前面的上传还在重试、队列已满时直接写入 spool
+    fn push(&self, profile: Profile, window: Window) {
+        if let Err(mpsc::error::TrySendError::Full((profile, window))) = self.tx.try_send((profile, window)) {
+            match self.uploader.defer(&profile, &window) {
+                Ok(()) => warn!("upload queue is full, spooled the profile"),
+                Err(e) => warn!("upload queue is full, dropping the profile: {e:#}"),
+            }
+        }
+    }
+
+    /// 等待排队的上传完成，最多等 `UPLOAD_GRACE`
+    async fn finish(self) {
+        drop(self.tx);
+        if time::timeout(UPLOAD_GRACE, self.worker).await.is_err() {
+            warn!("pending uploads did not finish within {UPLOAD_GRACE:?}, giving up");
+        }
+    }
+}
+
+fn extension(format: Format) -> &'static str {
+    match format {
+        Format::Folded => "folded",
+        Format::Speedscope => "speedscope.json",
+        Format::Firefox => "firefox.json",
+        Format::Pprof => "pb.gz",
+        Format::Svg => "svg",
+        Format::Json => "json",
+        Format::Jsonl => "jsonl",
+    }
+}
+
+/// `larkspur-20261019T120000Z.pb.gz`，按文件名排序即按时间排序
+fn file_name(started: SystemTime, format: Format) -> String {
+    let secs = started.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let (days, rem) = (secs / 86400, secs % 86400);
+    let (year, month, day) = civil_from_days(days as i64);
+    format!(
+        "{FILE_PREFIX}{year:04}{month:02}{day:02}T{:02}{:02}{:02}Z.{}",
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60,
+        extension(format),
+    )
+}
+
+// Howard Hinnant 的 days_from_civil 逆算法：1970-01-01 起的天数 -> 年月日
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// 只处理本程序写出的文件：先按文件数，再按修改时间淘汰最旧的
+fn prune(dir: &Path, max_files: usize, max_age: Option<Duration>) -> Result<()> {
+    let mut files: Vec<(PathBuf, SystemTime)> = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if !name.starts_with(FILE_PREFIX) || name.ends_with(".tmp") {
+            continue;
+        }
+        let modified = entry.metadata()?.modified()?;
+        files.push((entry.path(), modified));
+    }
+    files.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let excess = match max_files {
+        0 => 0,
+        n => files.len().saturating_sub(n),
+    };
+    let now = SystemTime::now();
+    for (i, (path, modified)) in files.iter().enumerate() {
+        let expired = max_age.is_some_and(|age| now.duration_since(*modified).unwrap_or_default() > age);
+        if i < excess || expired {
+            fs::remove_file(path)?;
+            info!("removed {}", path.display());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+
+    use super::*;
+
+    #[test]
+    fn civil_dates() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+        assert_eq!(civil_from_days(11016), (2000, 2, 29));
+        assert_eq!(civil_from_days(19723), (2024, 1, 1));
+        assert_eq!(civil_from_days(20745), (2026, 10, 19));
+    }
+
+    #[test]
+    fn file_names_sort_by_time() {
+        let t = UNIX_EPOCH + Duration::from_secs(20745 * 86400 + 12 * 3600 + 5 * 60 + 9);
+        assert_eq!(file_name(t, Format::Pprof), "larkspur-20261019T120509Z.pb.gz");
+        assert_eq!(file_name(UNIX_EPOCH, Format::Folded), "larkspur-19700101T000000Z.folded");
+    }
+
+    #[test]
+    fn failed_write_leaves_no_tmp() {
+        let dir = std::env::temp_dir().join(format!("larkspur-write-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        // 目标是已存在的目录，改名会失败
+        let path = dir.join("larkspur-20261019T120000Z.folded");
+        fs::create_dir_all(path.join("busy")).unwrap();
+        let profile = Profile { stacks: Vec::new(), unit: Default::default(), timeline: Vec::new() };
+
+        assert!(write_file(&path, &profile, Format::Folded).is_err());
+        assert!(!path.with_extension("tmp").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn touch(dir: &Path, name: &str, age: Duration) {
+        let file = File::create(dir.join(name)).unwrap();
+        file.set_modified(SystemTime::now() - age).unwrap();
+    }
+
+    fn names(dir: &Path) -> Vec<String> {
+        let mut names: Vec<_> =
+            fs::read_dir(dir).unwrap().map(|e| e.unwrap().file_name().to_string_lossy().into_owned()).collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn prune_by_count_and_age() {
+        let dir = std::env::temp_dir().join(format!("larkspur-prune-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let hour = Duration::from_secs(3600);
+        touch(&dir, "larkspur-20261019T100000Z.folded", Duration::ZERO);
+        touch(&dir, "larkspur-20261019T110000Z.folded", Duration::ZERO);
+        touch(&dir, "larkspur-20261019T120000Z.folded", 3 * hour);
+        touch(&dir, "larkspur-20261019T130000Z.folded", Duration::ZERO);
+        touch(&dir, "larkspur-20261019T140000Z.tmp", 3 * hour);
+        touch(&dir, "other.folded", 3 * hour);
+
+        prune(&dir, 0, None).unwrap();
+        assert_eq!(names(&dir).len(), 6);
+
+        // 按文件名淘汰最旧的，只动本程序写出的文件
+        prune(&dir, 3, None).unwrap();
+        assert_eq!(
+            names(&dir),
+            [
+                "larkspur-20261019T110000Z.folded",
+                "larkspur-20261019T120000Z.folded",
+                "larkspur-20261019T130000Z.folded",
+                "larkspur-20261019T140000Z.tmp",
+                "other.folded",
+            ]
+        );
+
+        prune(&dir, 0, Some(hour)).unwrap();
+        assert_eq!(
+            names(&dir),
+            [
+                "larkspur-20261019T110000Z.folded",
+                "larkspur-20261019T130000Z.folded",
+                "larkspur-20261019T140000Z.tmp",
+                "other.folded",
+            ]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,226 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Write},
+    path::Path,
+};
+
+use anyhow::{Context, Result, anyhow, bail};
+use serde_json::Value;
+
+/// folded 栈 -> 权重
+pub type Stacks = HashMap<String, f64>;
+
+/// 读取保存的 profile：folded 文本，或 `--format speedscope` 导出的 JSON
+pub fn load(path: &Path) -> Result<Stacks> {
+    let text = fs::read_to_string(path).with_context(|| format!("failed to read {}", path.display()))?;
+    let stacks = if text.trim_start().starts_with('{') {
+        parse_speedscope(&text)
+    } else {
+        parse_folded(&text)
+    };
+    stacks.with_context(|| format!("failed to parse {}", path.display()))
+}
+
+fn parse_folded(text: &str) -> Result<Stacks> {
+    let mut stacks = Stacks::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim_end();
+        if line.is_empty() {
+            continue;
+        }
+        let (stack, weight) = line
+            .rsplit_once(' ')
+            .ok_or_else(|| anyhow!("line {}: expected `stack weight`", n + 1))?;
+        let weight: f64 = weight.parse().with_context(|| format!("line {}: bad weight `{weight}`", n + 1))?;
+        *stacks.entry(stack.to_string()).or_default() += weight;
+    }
+    Ok(stacks)
+}
+
+fn parse_speedscope(text: &str) -> Result<Stacks> {
+    let doc: Value = serde_json::from_str(text)?;
+    let names: Vec<&str> = doc["shared"]["frames"]
+        .as_array()
+        .ok_or_else(|| anyhow!("missing shared.frames"))?
+        .iter()
+        .map(|f| f["name"].as_str().unwrap_or("[unknown]"))
+        .collect();
+
+    let mut stacks = Stacks::new();
+    for profile in doc["profiles"].as_array().into_iter().flatten() {
+        if profile["type"] != "sampled" {
+            bail!("only sampled speedscope profiles are supported");
+        }
+        let samples = profile["samples"].as_array().ok_or_else(|| anyhow!("missing samples"))?;
+        let weights = profile["weights"].as_array().ok_or_else(|| anyhow!("missing weights"))?;
+        for (sample, weight) in samples.iter().zip(weights) {
+            let frames: Vec<&str> = sample
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|i| names.get(i.as_u64()? as usize).copied())
+                .collect();
+            *stacks.entry(frames.join(";")).or_default() += weight.as_f64().unwrap_or(0.0);
+        }
+    }
+    Ok(stacks)
+}
+
+/// 一个栈在两次采集中的权重，`before` 已按需归一化
+pub struct Delta {
+    pub stack: String,
+    pub before: f64,
+    pub after: f64,
+}
+
+impl Delta {
+    pub fn change(&self) -> f64 {
+        self.after - self.before
+    }
+}
+
+/// `normalize` 为 true 时把 before 按总量缩放到与 after 相同，消除采集时长或采样频率的差异。
+/// 结果按变化量的绝对值降序排列
+pub fn diff(before: Stacks, mut after: Stacks, normalize: bool) -> Vec<Delta> {
+    let before_total: f64 = before.values().sum();
+    let after_total: f64 = after.values().sum();
+    let scale = if normalize && before_total > 0.0 { after_total / before_total } else { 1.0 };
+
+    let mut out: Vec<Delta> = before
+        .into_iter()
+        .map(|(stack, weight)| {
+            let after = after.remove(&stack).unwrap_or(0.0);
+            Delta { stack, before: weight * scale, after }
+        })
+        .collect();
+    out.extend(after.into_iter().map(|(stack, after)| Delta { stack, before: 0.0, after }));
+    out.sort_by(|a, b| b.change().abs().total_cmp(&a.change().abs()).then_with(|| a.stack.cmp(&b.stack)));
+    out
+}
+
+/// difffolded.pl 的输出格式，每行 `stack before after`，可直接交给 `flamegraph.pl` 画差分火焰图
+pub fn write_folded(deltas: &[Delta], w: &mut dyn Write) -> io::Result<()> {
+    for d in deltas {
+        writeln!(w, "{} {} {}", d.stack, d.before.round() as u64, d.after.round() as u64)?;
+    }
+    w.flush()
+}
+
+/// 增长和减少最多的各 `limit` 个栈，百分比相对 after 的总量
+pub fn write_ranked(deltas: &[Delta], limit: usize, w: &mut dyn Write) -> io::Result<()> {
+    let total: f64 = deltas.iter().map(|d| d.after).sum::<f64>().max(1.0);
+    let grew: Vec<_> = deltas.iter().filter(|d| d.change() > 0.0).take(limit).collect();
+    let shrank: Vec<_> = deltas.iter().filter(|d| d.change() < 0.0).take(limit).collect();
+
+    for (title, list) in [("grew", grew), ("shrank", shrank)] {
+        writeln!(w, "stacks that {title}:")?;
+        for d in list {
+            writeln!(
+                w,
+                "  {:>+8.2}%  {:>12.0} -> {:<12.0} {}",
+                d.change() * 100.0 / total,
+                d.before,
+                d.after,
+                tail(&d.stack, 4),
+            )?;
+        }
+    }
+    w.flush()
+}
+
+// 完整栈太长，只保留靠近叶端的几帧
+fn tail(stack: &str, frames: usize) -> String {
+    let parts: Vec<&str> = stack.split(';').collect();
+    if parts.len() <= frames {
+        return stack.to_string();
+    }
+    format!("…;{}", parts[parts.len() - frames..].join(";"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stacks(pairs: &[(&str, f64)]) -> Stacks {
+        pairs.iter().map(|&(s, w)| (s.to_string(), w)).collect()
+    }
+
+    fn find<'a>(deltas: &'a [Delta], stack: &str) -> &'a Delta {
+        deltas.iter().find(|d| d.stack == stack).unwrap()
+    }
+
+    #[test]
+    fn folded_sums_duplicates() {
+        let parsed = parse_folded("a;b 3\n\na;b 2\nc d;e 1.5\n").unwrap();
+        assert_eq!(parsed, stacks(&[("a;b", 5.0), ("c d;e", 1.5)]));
+    }
+
+    #[test]
+    fn folded_rejects_malformed_lines() {
+        let err = parse_folded("a;b 1\nnoweight\n").unwrap_err();
+        assert!(err.to_string().contains("line 2"));
+        let err = parse_folded("a;b x\n").unwrap_err();
+        assert!(err.to_string().contains("bad weight"));
+    }
+
+    #[test]
+    fn speedscope_frames() {
+        let doc = r#"{
+            "shared": {"frames": [{"name": "main"}, {"name": "work"}]},
+            "profiles": [{"type": "sampled", "samples": [[0, 1], [0], [0, 1]], "weights": [2, 1, 3]}]
+        }"#;
+        assert_eq!(parse_speedscope(doc).unwrap(), stacks(&[("main;work", 5.0), ("main", 1.0)]));
+    }
+
+    #[test]
+    fn speedscope_rejects_malformed() {
+        assert!(parse_speedscope("{}").is_err());
+        let evented = r#"{"shared": {"frames": []}, "profiles": [{"type": "evented"}]}"#;
+        assert!(parse_speedscope(evented).is_err());
+        let no_weights = r#"{"shared": {"frames": []}, "profiles": [{"type": "sampled", "samples": []}]}"#;
+        assert!(parse_speedscope(no_weights).is_err());
+    }
+
+    #[test]
+    fn one_sided_stacks() {
+        let deltas = diff(stacks(&[("gone", 4.0), ("both", 1.0)]), stacks(&[("new", 2.0), ("both", 1.0)]), false);
+        assert_eq!(deltas.len(), 3);
+        assert_eq!((find(&deltas, "gone").before, find(&deltas, "gone").after), (4.0, 0.0));
+        assert_eq!((find(&deltas, "new").before, find(&deltas, "new").after), (0.0, 2.0));
+        // 按变化量的绝对值降序
+        let order: Vec<_> = deltas.iter().map(|d| d.stack.as_str()).collect();
+        assert_eq!(order, ["gone", "new", "both"]);
+    }
+
+    #[test]
+    fn normalization_scales_before() {
+        let before = stacks(&[("a", 10.0), ("b", 30.0)]);
+        let after = stacks(&[("a", 5.0), ("b", 5.0)]);
+        let deltas = diff(before.clone(), after.clone(), true);
+        assert_eq!(find(&deltas, "a").before, 2.5);
+        assert_eq!(find(&deltas, "b").before, 7.5);
+
+        let deltas = diff(before, after, false);
+        assert_eq!(find(&deltas, "a").before, 10.0);
+    }
+
+    #[test]
+    fn zero_totals() {
+        let deltas = diff(Stacks::new(), stacks(&[("a", 3.0)]), true);
+        assert_eq!((deltas[0].before, deltas[0].after), (0.0, 3.0));
+
+        let deltas = diff(stacks(&[("a", 3.0)]), Stacks::new(), true);
+        assert_eq!((deltas[0].before, deltas[0].after), (0.0, 0.0));
+
+        let mut out = Vec::new();
+        write_ranked(&diff(Stacks::new(), Stacks::new(), true), 5, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "stacks that grew:\nstacks that shrank:\n");
+    }
+
+    #[test]
+    fn tail_keeps_leaf_frames() {
+        assert_eq!(tail("a;b", 4), "a;b");
+        assert_eq!(tail("a;b;c;d;e", 2), "…;d;e");
+    }
+}
@@ -0,0 +1,41 @@
+//! eBPF 剖析器 larkspur 的库接口：采集器、符号解析器和各种输出格式。
+//!
+//! ```no_run
+//! # async fn demo() -> anyhow::Result<()> {
+//! use std::time::Duration;
+//!
+//! let mut profiler = larkspur::OnCpuProfiler::builder().pid(1234).frequency(99).start()?;
+//! let profile = profiler.profile(Duration::from_secs(10)).await?;
+//! larkspur::output::folded::write(&profile, &mut std::io::stdout())?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! 也可以不聚合，逐个样本处理：
+//!
+//! ```no_run
+//! # async fn demo() -> anyhow::Result<()> {
+//! let stream = larkspur::OnCpuProfiler::builder().frequency(999).stream(false)?;
+//! stream
+//!     .for_each(|sample| println!("{} {} cpu{} {}", sample.ts, sample.comm, sample.cpu, sample.uaddrs.len()))
+//!     .await?;
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod collector;
+pub mod daemon;
+pub mod diff;
+pub mod metrics;
+pub mod output;
+pub mod profile;
+pub mod server;
+pub mod symbolize;
+pub mod upload;
+
+mod profiler;
+
+pub use collector::stream::{SampleEvent, SampleKind, SampleStream};
+pub use profile::{Profile, Stack, Unit};
+pub use profiler::{OnCpuProfiler, OnCpuProfilerBuilder};
+pub use symbolize::{kstack::KStackResolver, ustack::Resolver};
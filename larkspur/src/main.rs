@@ -1,12 +1,126 @@
-mod symbolize;
-mod collector;
 
+mod config;
+
+use std::{path::{Path, PathBuf}, time::Duration};
+
+use aya::programs::SamplePolicy;
 use clap::Parser;
-use tokio;
 
+use larkspur::collector::{self, SampleOpts, event::Event, off_cpu::{BlockState, Filter}, runqlat::GroupBy, trace::Probe};
+use larkspur::output::{self, Format, report::Report};
+use larkspur::{daemon, diff, server, upload};
+use larkspur::profile::Profile;
+
+
+/// 逐样本采集共用的选项
+#[derive(clap::Args)]
+struct SampleArgs {
+    /// 按线程拆分，在进程名之后插入 `线程名-tid` 帧
+    #[arg(long)]
+    threads: bool,
+    /// 只保留采集开始后第 N 秒之后的样本
+    #[arg(long)]
+    from: Option<f64>,
+    /// 只保留采集开始后第 N 秒之前的样本
+    #[arg(long)]
+    to: Option<f64>,
+    /// 同时输出按线程展开的时间线（Chrome trace-event JSON，可用 Perfetto 打开）
+    #[arg(long)]
+    timeline: Option<PathBuf>,
+    /// 在 json/jsonl 输出中逐个列出样本（时间、CPU、线程）
+    #[arg(long)]
+    per_sample: bool,
+}
+
+impl SampleArgs {
+    fn opts(&self) -> SampleOpts {
+        SampleOpts {
+            threads: self.threads,
+            timeline: self.timeline.is_some() || self.per_sample,
+            from: self.from,
+            to: self.to,
+        }
+    }
+}
+
+/// profile 的输出选项
+#[derive(clap::Args)]
+struct OutputArgs {
+    /// 输出文件，默认 stdout
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    /// 输出格式
+    #[arg(long, value_enum, default_value = "folded")]
+    format: Format,
+    /// 改为输出终端可读的文本报告
+    #[arg(long, value_enum, conflicts_with = "format")]
+    report: Option<Report>,
+    /// 报告中省略占比低于该百分比的调用树节点或函数
+    #[arg(long, default_value = "0.5", requires = "report")]
+    min_percent: f64,
+}
+
+impl OutputArgs {
+    fn write(&self, profile: &Profile, path: Option<&Path>) -> anyhow::Result<()> {
+        let mut w = output::open(path)?;
+        match self.report {
+            Some(report) => output::report::write(profile, report, self.min_percent, &mut w)?,
+            None => output::write(profile, self.format, &mut w)?,
+        }
+        Ok(())
+    }
+}
+
+/// 守护进程把 profile 推送到远端的选项
+#[derive(clap::Args)]
+struct UploadArgs {
+    /// Pyroscope 或 OTLP 接收端的根地址，如 http://localhost:4040
+    #[arg(long)]
+    upload_url: Option<String>,
+    /// 上传协议
+    #[arg(long, value_enum, default_value = "pyroscope", requires = "upload_url")]
+    protocol: upload::Protocol,
+    /// 服务名
+    #[arg(long, default_value = "larkspur", requires = "upload_url")]
+    service: String,
+    /// 附加标签 key=value，可重复
+    #[arg(long = "label", value_parser = upload::parse_label, requires = "upload_url")]
+    labels: Vec<(String, String)>,
+    /// 失败后的重试次数
+    #[arg(long, default_value = "3", requires = "upload_url")]
+    upload_retries: u32,
+    /// 接收端不可用时暂存 profile 的目录，恢复后补发
+    #[arg(long, requires = "upload_url")]
+    spool_dir: Option<PathBuf>,
+    /// spool 中最多保留的 profile 数
+    #[arg(long, default_value = "1024", requires = "spool_dir")]
+    spool_max: usize,
+}
+
+impl UploadArgs {
+    fn uploader(self) -> Option<upload::Uploader> {
+        Some(upload::Uploader {
+            protocol: self.protocol,
+            url: self.upload_url?,
+            service: self.service,
+            host: upload::hostname(),
+            labels: self.labels,
+            retries: self.upload_retries,
+            spool_dir: self.spool_dir,
+            spool_max: self.spool_max,
+        })
+    }
+}
 
 #[derive(clap::Parser)]
+#[command(args_override_self = true)]
 struct Opt {
+    /// TOML 配置文件，`[子命令名]` 表中的键对应该子命令的长选项，命令行参数优先
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+    /// 额外的调试符号目录（布局同 /usr/lib/debug），可重复
+    #[arg(long, global = true)]
+    debug_dir: Vec<PathBuf>,
     #[command(subcommand)]
     cmd: Command,
 }
@@ -17,32 +131,265 @@ enum Command {
     OnCpu {
         #[arg(short, long)]
         pid: u32,
+        /// 采样时长（秒），0 表示直到 Ctrl-C / SIGTERM
         #[arg(short, long, default_value = "1")]
         duration: u64,
         #[arg(short, long, default_value = "99")]
         frequency: u64,
+        /// 按事件计数采样：每发生 N 次事件采一次，与 --frequency 互斥
+        #[arg(long, conflicts_with = "frequency")]
+        period: Option<u64>,
+        /// 采样事件：cpu-clock、cycles、instructions、cache-misses、branch-misses、
+        /// page-faults、context-switches（或 perf 的别名 cpu-cycles、faults、cs）或原始编码 r<hex>
+        #[arg(short, long, default_value = "cpu-clock")]
+        event: Event,
+        /// 在内核中按栈计数，结束时一次性读出，适合高频率或多核机器
+        #[arg(long, conflicts_with_all = ["from", "to", "timeline", "per_sample"])]
+        aggregate: bool,
+        #[command(flatten)]
+        sample: SampleArgs,
+        /// ring buffer 大小（KiB，需为 2 的幂）
+        #[arg(long, default_value = "256")]
+        ring_buf_size: u32,
+        #[command(flatten)]
+        out: OutputArgs,
     },
     /// off-cpu 采样
     OffCpu {
         #[arg(short, long)]
         pid: u32,
+        /// 采样时长（秒），0 表示直到 Ctrl-C / SIGTERM
+        #[arg(short, long, default_value = "5")]
+        duration: u64,
+        /// 只统计切出时处于该状态的线程
+        #[arg(long, value_enum, default_value = "all")]
+        state: BlockState,
+        /// 忽略短于该时长（微秒）的阻塞
+        #[arg(long)]
+        min_block_us: Option<u64>,
+        /// 忽略长于该时长（微秒）的阻塞
+        #[arg(long)]
+        max_block_us: Option<u64>,
+        /// 同时记录唤醒者的栈，输出 “阻塞栈;--;唤醒者栈” 形式的 off-wake 栈
+        #[arg(long)]
+        wakers: bool,
+        /// 在内核中按栈统计阻塞时长的 log2 直方图，代替 folded 输出
+        #[arg(long, conflicts_with_all = ["wakers", "from", "to", "timeline", "per_sample"])]
+        histogram: bool,
+        #[command(flatten)]
+        sample: SampleArgs,
+        /// ring buffer 大小（KiB，需为 2 的幂）
+        #[arg(long, default_value = "256")]
+        ring_buf_size: u32,
+        #[command(flatten)]
+        out: OutputArgs,
+    },
+    /// on-cpu + off-cpu 合并的 wall-clock 剖析，权重为纳秒
+    Wall {
+        #[arg(short, long)]
+        pid: u32,
+        /// 采样时长（秒），0 表示直到 Ctrl-C / SIGTERM
         #[arg(short, long, default_value = "5")]
         duration: u64,
+        /// on-cpu 采样频率
+        #[arg(short, long, default_value = "99")]
+        frequency: u64,
+        /// 按线程拆分，在进程名之后插入 `线程名-tid` 帧
+        #[arg(long)]
+        threads: bool,
+        /// 每个 ring buffer 的大小（KiB，需为 2 的幂）
+        #[arg(long, default_value = "256")]
+        ring_buf_size: u32,
+        #[command(flatten)]
+        out: OutputArgs,
+    },
+    /// 运行队列延迟：任务可运行到真正运行之间的等待时间
+    Runqlat {
+        /// 只统计该进程，默认所有进程
+        #[arg(short, long)]
+        pid: Option<u32>,
+        /// 采集时长（秒），0 表示直到 Ctrl-C / SIGTERM
+        #[arg(short, long, default_value = "5")]
+        duration: u64,
+        /// 直方图按进程或 cgroup 分组
+        #[arg(long, value_enum, default_value = "pid")]
+        group_by: GroupBy,
+        /// 再按任务上次切出时的栈细分
+        #[arg(long)]
+        stacks: bool,
+        /// 输出文件，默认 stdout
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// 在 tracepoint / kprobe / uprobe 触发时采集调用栈
+    Trace {
+        /// 探测点：`category:name`（tracepoint）、内核函数名（kprobe）
+        /// 或 `path:symbol`（uprobe，路径含 `/` 或为 `libc.so.6` 这样的库名）
+        probe: Probe,
+        /// 只跟踪该进程，默认所有进程
+        #[arg(short, long)]
+        pid: Option<u32>,
+        /// 跟踪时长（秒），0 表示直到 Ctrl-C / SIGTERM
+        #[arg(short, long, default_value = "5")]
+        duration: u64,
+        #[command(flatten)]
+        sample: SampleArgs,
+        /// ring buffer 大小（KiB，需为 2 的幂）
+        #[arg(long, default_value = "256")]
+        ring_buf_size: u32,
+        #[command(flatten)]
+        out: OutputArgs,
+    },
+    /// 常驻的低开销 on-cpu 采样，定期把 profile 写到目录中或上传到 Pyroscope / OTLP
+    Daemon {
+        /// 只采集该进程，默认所有进程
+        #[arg(short, long)]
+        pid: Option<u32>,
+        #[arg(short, long, default_value = "19")]
+        frequency: u64,
+        /// 按线程拆分，在进程名之后插入 `线程名-tid` 帧
+        #[arg(long)]
+        threads: bool,
+        /// 每隔多少秒写出一个 profile
+        #[arg(long, default_value = "60")]
+        interval: u64,
+        /// 输出目录
+        #[arg(long, required_unless_present = "upload_url")]
+        dir: Option<PathBuf>,
+        /// 输出格式
+        #[arg(long, value_enum, default_value = "pprof")]
+        format: Format,
+        /// 最多保留的文件数，0 表示不限
+        #[arg(long, default_value = "1440")]
+        max_files: usize,
+        /// 删除早于该小时数的文件
+        #[arg(long)]
+        max_age_hours: Option<u64>,
+        #[command(flatten)]
+        upload: UploadArgs,
+        /// 在该地址提供 Prometheus /metrics
+        #[arg(long)]
+        metrics_listen: Option<std::net::SocketAddr>,
+    },
+    /// 类似 net/http/pprof 的 HTTP 服务，按请求运行采集并返回结果，同时提供 /metrics
+    Serve {
+        /// 监听地址
+        #[arg(long, default_value = "127.0.0.1:6060")]
+        listen: std::net::SocketAddr,
+        /// 单次请求允许的最长采集时间（秒）
+        #[arg(long, default_value = "300")]
+        max_seconds: u64,
+    },
+    /// 比较两次采集，输出差分 folded 栈（可用 flamegraph.pl 画差分火焰图）
+    /// 并在 stderr 列出增长和减少最多的栈
+    Diff {
+        /// 改动前的 profile：folded 或 speedscope JSON
+        before: PathBuf,
+        /// 改动后的 profile
+        after: PathBuf,
+        /// 不按总量归一化，直接比较原始权重
+        #[arg(long)]
+        no_normalize: bool,
+        /// 增长和减少各列出的栈数
+        #[arg(long, default_value = "10")]
+        limit: usize,
+        /// 差分 folded 输出文件，默认 stdout
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// malloc/free 内存分配剖析
+    Alloc {
+        #[arg(short, long)]
+        pid: u32,
+        /// 采集时长（秒），0 表示直到 Ctrl-C / SIGTERM
+        #[arg(short, long, default_value = "10")]
+        duration: u64,
+        /// 最多跟踪的未释放分配数
+        #[arg(long, default_value = "262144")]
+        max_allocs: u32,
+        /// 结束时仍未释放字节数的输出，格式与 --output 相同
+        #[arg(long)]
+        outstanding: Option<PathBuf>,
+        // --output 为按栈累计的分配字节数
+        #[command(flatten)]
+        out: OutputArgs,
     },
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     env_logger::init();
-    let opt = Opt::parse();
+    let opt = Opt::parse_from(config::merge_args(std::env::args_os().collect())?);
+    larkspur::symbolize::ustack::set_debug_dirs(opt.debug_dir);
 
-    match opt.cmd {
-        Command::OnCpu { pid, duration, frequency } => {
-            collector::on_cpu::run(pid, duration, frequency).await?;
+    let (profile, out, timeline) = match opt.cmd {
+        Command::OnCpu { pid, duration, frequency, period, event, aggregate, sample, ring_buf_size, out } => {
+            let policy = match period {
+                Some(p) => SamplePolicy::Period(p),
+                None => SamplePolicy::Frequency(frequency),
+            };
+            (collector::on_cpu::run(pid, duration, event, policy, aggregate, sample.opts(), ring_buf_size).await?, out, sample.timeline)
+        }
+        Command::OffCpu { pid, duration, state, min_block_us, max_block_us, wakers, histogram, sample, ring_buf_size, out } => {
+            let filter = Filter { state, min_block_us, max_block_us };
+            if histogram {
+                let hists = collector::off_cpu::histogram(pid, duration, filter, sample.threads).await?;
+                let mut w = output::open(out.output.as_deref())?;
+                output::histogram::write(&hists, &mut w)?;
+                return Ok(());
+            }
+            (collector::off_cpu::run(pid, duration, filter, wakers, sample.opts(), ring_buf_size).await?, out, sample.timeline)
+        }
+        Command::Wall { pid, duration, frequency, threads, ring_buf_size, out } => {
+            (collector::wall::run(pid, duration, frequency, threads, ring_buf_size).await?, out, None)
+        }
+        Command::Runqlat { pid, duration, group_by, stacks, output } => {
+            let hists = collector::runqlat::run(pid, duration, group_by, stacks).await?;
+            let mut w = output::open(output.as_deref())?;
+            output::histogram::write(&hists, &mut w)?;
+            return Ok(());
         }
-        Command::OffCpu { pid, duration } => {
-            collector::off_cpu::run(pid, duration).await?;
+        Command::Trace { probe, pid, duration, sample, ring_buf_size, out } => {
+            (collector::trace::run(probe, pid, duration, sample.opts(), ring_buf_size).await?, out, sample.timeline)
         }
+        Command::Daemon { pid, frequency, threads, interval, dir, format, max_files, max_age_hours, upload, metrics_listen } => {
+            return daemon::run(daemon::Config {
+                pid,
+                frequency,
+                threads,
+                interval: Duration::from_secs(interval),
+                dir,
+                format,
+                max_files,
+                max_age: max_age_hours.map(|h| Duration::from_secs(h.saturating_mul(3600))),
+                upload: upload.uploader(),
+                metrics_listen,
+            })
+            .await;
+        }
+        Command::Serve { listen, max_seconds } => {
+            return server::run(server::Config { listen, max_seconds, profiling: true }).await;
+        }
+        Command::Diff { before, after, no_normalize, limit, output } => {
+            let deltas = diff::diff(diff::load(&before)?, diff::load(&after)?, !no_normalize);
+            let mut w = output::open(output.as_deref())?;
+            diff::write_folded(&deltas, &mut w)?;
+            diff::write_ranked(&deltas, limit, &mut std::io::stderr().lock())?;
+            return Ok(());
+        }
+        Command::Alloc { pid, duration, max_allocs, outstanding, out } => {
+            let profile = collector::alloc::run(pid, duration, max_allocs).await?;
+            if let Some(path) = outstanding {
+                out.write(&profile.outstanding, Some(&path))?;
+            }
+            (profile.allocated, out, None)
+        }
+    };
+
+    out.write(&profile, out.output.as_deref())?;
+    if let Some(path) = timeline {
+        let mut w = output::open(Some(&path))?;
+        output::timeline::write(&profile, &mut w)?;
     }
     Ok(())
-}
\ No newline at end of file
+}
@@ -0,0 +1,117 @@
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+    path::Path,
+    sync::{LazyLock, Mutex},
+};
+
+/// 每个按进程的指标最多输出的进程数，按数值取最大的
+const MAX_PROCESSES: usize = 100;
+
+/// 进程内累计的指标，由采集代码直接更新，`/metrics` 时渲染成 Prometheus 文本格式
+#[derive(Default)]
+struct Registry {
+    /// 按采集器统计
+    samples: BTreeMap<&'static str, u64>,
+    lost: BTreeMap<&'static str, u64>,
+    stack_errors: u64,
+    cache_hits: u64,
+    cache_misses: u64,
+    /// pid -> (进程名, 值)
+    cpu_samples: HashMap<u32, (String, u64)>,
+    off_cpu_ns: HashMap<u32, (String, u64)>,
+}
+
+static REGISTRY: LazyLock<Mutex<Registry>> = LazyLock::new(Default::default);
+
+fn with<T>(f: impl FnOnce(&mut Registry) -> T) -> T {
+    f(&mut REGISTRY.lock().unwrap_or_else(|e| e.into_inner()))
+}
+
+/// 一次采集（或守护进程的一个周期）收到和丢失的样本数
+pub fn samples(collector: &'static str, collected: u64, lost: u64) {
+    with(|r| {
+        *r.samples.entry(collector).or_default() += collected;
+        *r.lost.entry(collector).or_default() += lost;
+    });
+}
+
+/// 内核取栈失败，或栈在读出前已被覆盖
+pub fn stack_error() {
+    with(|r| r.stack_errors += 1);
+}
+
+/// 按进程缓存的用户栈符号解析器是否命中
+pub fn resolver_lookup(hit: bool) {
+    with(|r| match hit {
+        true => r.cache_hits += 1,
+        false => r.cache_misses += 1,
+    });
+}
+
+pub fn cpu_samples(pid: u32, comm: &str, count: u64) {
+    with(|r| add(&mut r.cpu_samples, pid, comm, count));
+}
+
+pub fn off_cpu_ns(pid: u32, comm: &str, ns: u64) {
+    with(|r| add(&mut r.off_cpu_ns, pid, comm, ns));
+}
+
+fn add(map: &mut HashMap<u32, (String, u64)>, pid: u32, comm: &str, value: u64) {
+    let entry = map.entry(pid).or_insert_with(|| (comm.to_string(), 0));
+    entry.1 += value;
+}
+
+/// Prometheus 文本格式，顺带丢弃已退出进程的计数
+pub fn render() -> String {
+    with(|r| {
+        let live = |pid: &u32, _: &mut (String, u64)| *pid == 0 || Path::new(&format!("/proc/{pid}")).exists();
+        r.cpu_samples.retain(live);
+        r.off_cpu_ns.retain(live);
+
+        let mut out = String::new();
+        header(&mut out, "larkspur_samples_total", "counter", "Samples received from the kernel.");
+        for (collector, n) in &r.samples {
+            let _ = writeln!(out, "larkspur_samples_total{{collector=\"{collector}\"}} {n}");
+        }
+        header(&mut out, "larkspur_samples_lost_total", "counter", "Samples dropped because a ring buffer or map was full.");
+        for (collector, n) in &r.lost {
+            let _ = writeln!(out, "larkspur_samples_lost_total{{collector=\"{collector}\"}} {n}");
+        }
+        header(&mut out, "larkspur_stack_errors_total", "counter", "Stacks the kernel failed to record or that were evicted before being read.");
+        let _ = writeln!(out, "larkspur_stack_errors_total {}", r.stack_errors);
+
+        header(&mut out, "larkspur_symbolizer_cache_hits_total", "counter", "Per-process symbolizer cache hits.");
+        let _ = writeln!(out, "larkspur_symbolizer_cache_hits_total {}", r.cache_hits);
+        header(&mut out, "larkspur_symbolizer_cache_misses_total", "counter", "Per-process symbolizer cache misses.");
+        let _ = writeln!(out, "larkspur_symbolizer_cache_misses_total {}", r.cache_misses);
+        let lookups = r.cache_hits + r.cache_misses;
+        let ratio = if lookups == 0 { 0.0 } else { r.cache_hits as f64 / lookups as f64 };
+        header(&mut out, "larkspur_symbolizer_cache_hit_ratio", "gauge", "Fraction of symbolizer cache lookups that hit.");
+        let _ = writeln!(out, "larkspur_symbolizer_cache_hit_ratio {ratio}");
+
+        process_metric(&mut out, "larkspur_process_cpu_samples_total", "On-CPU samples per process.", &r.cpu_samples, 1.0);
+        process_metric(&mut out, "larkspur_process_off_cpu_seconds_total", "Time blocked off-CPU per process.", &r.off_cpu_ns, 1e-9);
+        out
+    })
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+/// `scale` 把内部单位换算成指标单位，如纳秒到秒
+fn process_metric(out: &mut String, name: &str, help: &str, values: &HashMap<u32, (String, u64)>, scale: f64) {
+    header(out, name, "counter", help);
+    let mut top: Vec<_> = values.iter().collect();
+    top.sort_by_key(|(_, (_, value))| Reverse(*value));
+    for (pid, (comm, value)) in top.into_iter().take(MAX_PROCESSES) {
+        let _ = writeln!(out, "{name}{{pid=\"{pid}\",comm=\"{}\"}} {}", escape(comm), *value as f64 * scale);
+    }
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
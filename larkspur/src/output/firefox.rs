@@ -0,0 +1,229 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{self, Write},
+};
+
+use serde_json::{Value, json};
+
+use crate::profile::{Frame, FrameKind, Profile, Unit};
+
+// meta.categories 的下标
+const CATEGORY_OTHER: usize = 0;
+const CATEGORY_USER: usize = 1;
+const CATEGORY_KERNEL: usize = 2;
+
+/// 输出 Firefox Profiler 的 processed profile 格式，可直接拖进 profiler.firefox.com。
+/// 有时间线时每个线程一条轨道、样本带真实时间；否则所有栈放在同一轨道，每个栈一个样本
+pub fn write(profile: &Profile, w: &mut dyn Write) -> io::Result<()> {
+    let symbols: Vec<Vec<Frame>> = profile.stacks.iter().map(|s| s.symbols()).collect();
+    let (weight_type, scale) = match profile.unit {
+        Unit::Samples => ("samples", 1.0),
+        Unit::Nanoseconds => ("tracing-ms", 1e-6),
+        Unit::Bytes => ("bytes", 1.0),
+    };
+
+    let mut threads = Vec::new();
+    if profile.timeline.is_empty() {
+        let mut thread = Thread::default();
+        for (i, stack) in symbols.iter().enumerate() {
+            let idx = thread.intern_stack(stack);
+            thread.sample(idx, i as f64, profile.stacks[i].weight as f64 * scale);
+        }
+        threads.push(thread.finish("larkspur", 0, 0, weight_type));
+    } else {
+        let mut by_thread: BTreeMap<(u32, u32), Vec<_>> = BTreeMap::new();
+        for span in &profile.timeline {
+            by_thread.entry((span.pid, span.tid)).or_default().push(span);
+        }
+        for ((pid, tid), mut spans) in by_thread {
+            spans.sort_by_key(|s| s.start_ns);
+            let stack = &profile.stacks[spans[0].stack];
+            let name = match &stack.thread {
+                Some(thread) => format!("{} ({})", thread.name, stack.comm),
+                None => stack.comm.clone(),
+            };
+            let mut thread = Thread::default();
+            let mut cache = HashMap::new();
+            for span in spans {
+                let idx = *cache
+                    .entry(span.stack)
+                    .or_insert_with(|| thread.intern_stack(&symbols[span.stack]));
+                thread.sample(idx, span.start_ns as f64 / 1e6, span.weight as f64 * scale);
+            }
+            threads.push(thread.finish(&name, pid, tid, weight_type));
+        }
+    }
+
+    let doc = json!({
+        "meta": {
+            "interval": 1.0,
+            "startTime": 0.0,
+            "processType": 0,
+            "product": "larkspur",
+            "stackwalk": 1,
+            "debug": false,
+            "version": 27,
+            "preprocessedProfileVersion": 44,
+            "symbolicated": true,
+            "categories": [
+                { "name": "Other", "color": "grey", "subcategories": ["Other"] },
+                { "name": "User", "color": "yellow", "subcategories": ["Other"] },
+                { "name": "Kernel", "color": "orange", "subcategories": ["Other"] },
+            ],
+            "markerSchema": [],
+            "extensions": { "length": 0, "baseURL": [], "id": [], "name": [] },
+        },
+        "libs": [],
+        "pages": [],
+        "counters": [],
+        "threads": threads,
+    });
+    serde_json::to_writer(&mut *w, &doc)?;
+    writeln!(w)?;
+    w.flush()
+}
+
+/// 单个线程的各张表；processed 格式中字符串、函数、帧和栈都按线程去重
+#[derive(Default)]
+struct Thread {
+    strings: Vec<String>,
+    string_index: HashMap<String, usize>,
+
+    func_index: HashMap<(usize, Option<usize>), usize>,
+    func_name: Vec<usize>,
+    func_file: Vec<Option<usize>>,
+    func_line: Vec<Option<u32>>,
+
+    frame_index: HashMap<(usize, Option<u32>, usize), usize>,
+    frame_func: Vec<usize>,
+    frame_line: Vec<Option<u32>>,
+    frame_category: Vec<usize>,
+
+    stack_index: HashMap<(Option<usize>, usize), usize>,
+    stack_prefix: Vec<Option<usize>>,
+    stack_frame: Vec<usize>,
+    stack_category: Vec<usize>,
+
+    sample_stack: Vec<usize>,
+    sample_time: Vec<f64>,
+    sample_weight: Vec<f64>,
+}
+
+impl Thread {
+    fn string(&mut self, s: &str) -> usize {
+        if let Some(&i) = self.string_index.get(s) {
+            return i;
+        }
+        self.strings.push(s.to_string());
+        self.string_index.insert(s.to_string(), self.strings.len() - 1);
+        self.strings.len() - 1
+    }
+
+    fn frame(&mut self, frame: &Frame) -> usize {
+        let name = self.string(&frame.name);
+        let file = frame.file.as_deref().map(|f| self.string(f));
+        let func = *self.func_index.entry((name, file)).or_insert_with(|| {
+            self.func_name.push(name);
+            self.func_file.push(file);
+            self.func_line.push(frame.line);
+            self.func_name.len() - 1
+        });
+
+        let category = match frame.kind {
+            FrameKind::User => CATEGORY_USER,
+            FrameKind::Kernel => CATEGORY_KERNEL,
+            _ => CATEGORY_OTHER,
+        };
+        *self.frame_index.entry((func, frame.line, category)).or_insert_with(|| {
+            self.frame_func.push(func);
+            self.frame_line.push(frame.line);
+            self.frame_category.push(category);
+            self.frame_func.len() - 1
+        })
+    }
+
+    /// 从根到叶逐层建立前缀栈，返回叶端的栈下标
+    fn intern_stack(&mut self, frames: &[Frame]) -> usize {
+        let mut prefix = None;
+        for frame in frames {
+            let frame = self.frame(frame);
+            let category = self.frame_category[frame];
+            let idx = *self.stack_index.entry((prefix, frame)).or_insert_with(|| {
+                self.stack_prefix.push(prefix);
+                self.stack_frame.push(frame);
+                self.stack_category.push(category);
+                self.stack_frame.len() - 1
+            });
+            prefix = Some(idx);
+        }
+        prefix.expect("stack always has a process frame")
+    }
+
+    fn sample(&mut self, stack: usize, time_ms: f64, weight: f64) {
+        self.sample_stack.push(stack);
+        self.sample_time.push(time_ms);
+        self.sample_weight.push(weight);
+    }
+
+    fn finish(self, name: &str, pid: u32, tid: u32, weight_type: &str) -> Value {
+        let nulls = |n: usize| vec![Value::Null; n];
+        let frames = self.frame_func.len();
+        let funcs = self.func_name.len();
+        json!({
+            "name": name,
+            "processType": "default",
+            "processStartupTime": 0.0,
+            "processShutdownTime": null,
+            "registerTime": 0.0,
+            "unregisterTime": null,
+            "pausedRanges": [],
+            "isMainThread": false,
+            "pid": pid.to_string(),
+            "tid": tid,
+            "samples": {
+                "length": self.sample_stack.len(),
+                "stack": self.sample_stack,
+                "time": self.sample_time,
+                "weight": self.sample_weight,
+                "weightType": weight_type,
+            },
+            "markers": {
+                "length": 0, "category": [], "data": [], "endTime": [],
+                "name": [], "phase": [], "startTime": [],
+            },
+            "stackTable": {
+                "length": self.stack_frame.len(),
+                "prefix": self.stack_prefix,
+                "frame": self.stack_frame,
+                "category": self.stack_category,
+                "subcategory": vec![0; self.stack_frame.len()],
+            },
+            "frameTable": {
+                "length": frames,
+                "address": vec![-1; frames],
+                "inlineDepth": vec![0; frames],
+                "category": self.frame_category,
+                "subcategory": vec![0; frames],
+                "func": self.frame_func,
+                "nativeSymbol": nulls(frames),
+                "innerWindowID": vec![0; frames],
+                "implementation": nulls(frames),
+                "line": self.frame_line,
+                "column": nulls(frames),
+            },
+            "funcTable": {
+                "length": funcs,
+                "name": self.func_name,
+                "isJS": vec![false; funcs],
+                "relevantForJS": vec![false; funcs],
+                "resource": vec![-1; funcs],
+                "fileName": self.func_file,
+                "lineNumber": self.func_line,
+                "columnNumber": nulls(funcs),
+            },
+            "resourceTable": { "length": 0, "lib": [], "name": [], "host": [], "type": [] },
+            "nativeSymbols": { "length": 0, "libIndex": [], "address": [], "name": [], "functionSize": [] },
+            "stringArray": self.strings,
+        })
+    }
+}
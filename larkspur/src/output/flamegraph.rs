@@ -0,0 +1,23 @@
+use std::io::{self, Write};
+
+use inferno::flamegraph::{self, Options};
+
+use crate::output::folded;
+use crate::profile::{Profile, Unit};
+
+/// 用 inferno 渲染可交互的火焰图 SVG
+pub fn write(profile: &Profile, w: &mut dyn Write) -> io::Result<()> {
+    let mut lines = Vec::new();
+    folded::write(profile, &mut lines)?;
+
+    let mut opts = Options::default();
+    opts.title = "larkspur".to_string();
+    opts.count_name = match profile.unit {
+        Unit::Samples => "samples",
+        Unit::Nanoseconds => "ns",
+        Unit::Bytes => "bytes",
+    }
+    .to_string();
+    flamegraph::from_reader(&mut opts, &lines[..], &mut *w).map_err(io::Error::other)?;
+    w.flush()
+}
@@ -0,0 +1,17 @@
+use std::io::{self, Write};
+
+use crate::profile::Profile;
+
+/// 输出 flamegraph.pl / inferno 使用的 folded 格式，每行 `frame;frame;... weight`
+pub fn write(profile: &Profile, w: &mut dyn Write) -> io::Result<()> {
+    for stack in &profile.stacks {
+        let frames: Vec<String> = stack.frames().iter().map(|f| sanitize(f)).collect();
+        writeln!(w, "{} {}", frames.join(";"), stack.weight)?;
+    }
+    w.flush()
+}
+
+// `;` 是帧分隔符，换行会破坏行格式
+fn sanitize(frame: &str) -> String {
+    frame.replace(';', ":").replace('\n', " ")
+}
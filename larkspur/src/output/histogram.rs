@@ -0,0 +1,46 @@
+use std::io::{self, Write};
+
+use crate::profile::Histogram;
+
+const BAR_WIDTH: u64 = 40;
+
+/// 按 bcc `offcputime` / `runqlat` 的样式输出每个栈的 log2 直方图，栈从叶到根逐行列出
+pub fn write(hists: &[Histogram], w: &mut dyn Write) -> io::Result<()> {
+    for hist in hists {
+        let mut frames = hist.stack.frames();
+        frames.reverse();
+        for frame in &frames {
+            writeln!(w, "    {frame}")?;
+        }
+        writeln!(w, "    total {} us", hist.stack.weight / 1000)?;
+        write_slots(&hist.slots, "usecs", w)?;
+        writeln!(w)?;
+    }
+    w.flush()
+}
+
+pub fn write_slots(slots: &[u64], unit: &str, w: &mut dyn Write) -> io::Result<()> {
+    let Some(last) = slots.iter().rposition(|&c| c > 0) else {
+        return Ok(());
+    };
+    let max = slots.iter().copied().max().unwrap_or(0);
+
+    writeln!(w, "{:>20} : count    distribution", unit)?;
+    for (i, &count) in slots.iter().enumerate().take(last + 1) {
+        let (low, high) = match i {
+            0 => (0, 0),
+            _ => (1u64 << (i - 1), (1u64 << i) - 1),
+        };
+        let stars = (count * BAR_WIDTH / max) as usize;
+        writeln!(
+            w,
+            "{:>10} -> {:<7} : {:<8} |{:<width$}|",
+            low,
+            high,
+            count,
+            "*".repeat(stars),
+            width = BAR_WIDTH as usize
+        )?;
+    }
+    Ok(())
+}
@@ -0,0 +1,113 @@
+use std::io::{self, Write};
+
+use serde_json::{Value, json};
+
+use crate::profile::{Profile, Span, Stack, Unit};
+
+/// 输出一个 JSON 文档：`{"unit", "stacks": [...], "samples": [...]}`。
+/// 栈中保留完整的符号信息，栈帧最内层在前；`samples` 只在记录了逐样本信息时非空
+pub fn write(profile: &Profile, w: &mut dyn Write) -> io::Result<()> {
+    let doc = json!({
+        "unit": unit(profile.unit),
+        "stacks": profile.stacks.iter().enumerate().map(|(i, s)| stack(i, s, profile.unit)).collect::<Vec<_>>(),
+        "samples": profile.timeline.iter().map(sample).collect::<Vec<_>>(),
+    });
+    serde_json::to_writer(&mut *w, &doc)?;
+    writeln!(w)?;
+    w.flush()
+}
+
+/// 每行一个 JSON 对象：先是所有栈（`"type": "stack"`），再是逐个样本（`"type": "sample"`），
+/// 样本的 `stack` 字段引用栈的 `id`
+pub fn write_lines(profile: &Profile, w: &mut dyn Write) -> io::Result<()> {
+    let records = profile
+        .stacks
+        .iter()
+        .enumerate()
+        .map(|(i, s)| ("stack", stack(i, s, profile.unit)))
+        .chain(profile.timeline.iter().map(|s| ("sample", sample(s))));
+    for (kind, mut record) in records {
+        record["type"] = json!(kind);
+        serde_json::to_writer(&mut *w, &record)?;
+        writeln!(w)?;
+    }
+    w.flush()
+}
+
+fn unit(unit: Unit) -> &'static str {
+    match unit {
+        Unit::Samples => "samples",
+        Unit::Nanoseconds => "nanoseconds",
+        Unit::Bytes => "bytes",
+    }
+}
+
+fn stack(id: usize, stack: &Stack, unit: Unit) -> Value {
+    let mut value = frames(stack);
+    value["id"] = json!(id);
+    value["weight"] = json!(stack.weight);
+    value["unit"] = json!(self::unit(unit));
+    if let Some(label) = &stack.label {
+        value["label"] = json!(label);
+    }
+    value
+}
+
+/// 进程、线程和两段栈；off-wake 栈的唤醒者以同样的结构放在 `waker` 中
+fn frames(stack: &Stack) -> Value {
+    let kernel: Vec<_> = stack
+        .kframes
+        .iter()
+        .map(|s| {
+            json!({
+                "address": format!("0x{:x}", s.offset),
+                "function": s.function,
+                "module": s.module,
+            })
+        })
+        .collect();
+    let user: Vec<_> = stack
+        .uframes
+        .iter()
+        .zip(&stack.uaddrs)
+        .flat_map(|(syms, &addr)| {
+            syms.iter().map(move |s| {
+                json!({
+                    "address": format!("0x{addr:x}"),
+                    "offset": format!("0x{:x}", s.offset),
+                    "function": s.function,
+                    "file": s.file,
+                    "line": s.line,
+                    "inline": s.inline,
+                })
+            })
+        })
+        .collect();
+
+    let mut value = json!({
+        "pid": stack.pid,
+        "comm": stack.comm,
+        "kernel": kernel,
+        "user": user,
+    });
+    if let Some(thread) = &stack.thread {
+        value["tid"] = json!(thread.tid);
+        value["thread"] = json!(thread.name);
+    }
+    if let Some(waker) = &stack.waker {
+        value["waker"] = frames(waker);
+    }
+    value
+}
+
+fn sample(span: &Span) -> Value {
+    json!({
+        "stack": span.stack,
+        "ts_ns": span.start_ns,
+        "dur_ns": span.dur_ns,
+        "pid": span.pid,
+        "tid": span.tid,
+        "cpu": span.cpu,
+        "weight": span.weight,
+    })
+}
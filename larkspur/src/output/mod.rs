@@ -0,0 +1,58 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+
+use crate::profile::Profile;
+
+pub mod firefox;
+pub mod flamegraph;
+pub mod folded;
+pub mod histogram;
+pub mod json;
+pub mod pprof;
+pub mod proto;
+pub mod report;
+pub mod speedscope;
+pub mod timeline;
+
+/// profile 的输出格式
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    /// flamegraph.pl / inferno 使用的 folded 栈
+    #[default]
+    Folded,
+    /// speedscope.app
+    Speedscope,
+    /// profiler.firefox.com
+    Firefox,
+    /// gzip 压缩的 pprof，`go tool pprof`
+    Pprof,
+    /// 可直接在浏览器中打开的火焰图 SVG
+    Svg,
+    /// 带完整符号信息（地址、模块、文件、行号、内联）的 JSON 文档
+    Json,
+    /// 同 json，但每行一个栈或样本，便于 jq 和导入数据仓库
+    Jsonl,
+}
+
+pub fn write(profile: &Profile, format: Format, w: &mut dyn Write) -> io::Result<()> {
+    match format {
+        Format::Folded => folded::write(profile, w),
+        Format::Speedscope => speedscope::write(profile, w),
+        Format::Firefox => firefox::write(profile, w),
+        Format::Pprof => pprof::write(profile, w),
+        Format::Svg => flamegraph::write(profile, w),
+        Format::Json => json::write(profile, w),
+        Format::Jsonl => json::write_lines(profile, w),
+    }
+}
+
+/// 打开输出目标，未指定路径时写到 stdout
+pub fn open(path: Option<&Path>) -> io::Result<Box<dyn Write>> {
+    Ok(match path {
+        Some(p) => Box::new(BufWriter::new(File::create(p)?)),
+        None => Box::new(BufWriter::new(io::stdout().lock())),
+    })
+}
@@ -0,0 +1,146 @@
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+};
+
+use flate2::{Compression, write::GzEncoder};
+
+use crate::output::proto::Message;
+use crate::profile::{Frame, FrameKind, Profile, Unit};
+
+/// 输出 gzip 压缩的 pprof（profile.proto），可用 `go tool pprof` 打开。
+/// 进程名和线程放在样本标签中，其余帧按叶端在前转成 location
+pub fn write(profile: &Profile, w: &mut dyn Write) -> io::Result<()> {
+    let mut gz = GzEncoder::new(w, Compression::default());
+    gz.write_all(&encode(profile))?;
+    gz.finish()?.flush()
+}
+
+pub fn encode(profile: &Profile) -> Vec<u8> {
+    let mut b = Builder::default();
+    let mut out = Message::default();
+
+    let (kind, unit) = match profile.unit {
+        Unit::Samples => ("samples", "count"),
+        Unit::Nanoseconds => ("time", "nanoseconds"),
+        Unit::Bytes => ("space", "bytes"),
+    };
+    let value_type = b.value_type(kind, unit);
+    out.message(1, &value_type);
+
+    for stack in &profile.stacks {
+        let mut sample = Message::default();
+        let mut locations = Vec::new();
+        let mut labels = Vec::new();
+        for frame in stack.symbols().iter().rev() {
+            match frame.kind {
+                FrameKind::Process => labels.push(b.label("comm", &frame.name)),
+                FrameKind::Thread => labels.push(b.label("thread", &frame.name)),
+                _ => locations.push(b.location(frame)),
+            }
+        }
+        sample.packed(1, &locations);
+        sample.packed(2, &[stack.weight]);
+        for label in labels.iter().rev() {
+            sample.message(3, label);
+        }
+        out.message(2, &sample);
+    }
+
+    for location in &b.locations {
+        out.message(4, location);
+    }
+    for function in &b.functions {
+        out.message(5, function);
+    }
+    for s in &b.strings {
+        out.bytes(6, s.as_bytes());
+    }
+    out.buf
+}
+
+/// 字符串表、function 和 location 的去重；id 从 1 开始，字符串表第 0 项必须为空串
+struct Builder {
+    strings: Vec<String>,
+    string_index: HashMap<String, u64>,
+    function_index: HashMap<(String, Option<String>), u64>,
+    functions: Vec<Message>,
+    location_index: HashMap<Frame, u64>,
+    locations: Vec<Message>,
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Builder {
+            strings: vec![String::new()],
+            string_index: HashMap::from([(String::new(), 0)]),
+            function_index: HashMap::new(),
+            functions: Vec::new(),
+            location_index: HashMap::new(),
+            locations: Vec::new(),
+        }
+    }
+}
+
+impl Builder {
+    fn string(&mut self, s: &str) -> u64 {
+        if let Some(&i) = self.string_index.get(s) {
+            return i;
+        }
+        let i = self.strings.len() as u64;
+        self.strings.push(s.to_string());
+        self.string_index.insert(s.to_string(), i);
+        i
+    }
+
+    fn value_type(&mut self, kind: &str, unit: &str) -> Message {
+        let mut m = Message::default();
+        m.varint(1, self.string(kind));
+        m.varint(2, self.string(unit));
+        m
+    }
+
+    fn label(&mut self, key: &str, value: &str) -> Message {
+        let mut m = Message::default();
+        m.varint(1, self.string(key));
+        m.varint(2, self.string(value));
+        m
+    }
+
+    fn function(&mut self, frame: &Frame) -> u64 {
+        let key = (frame.folded(), frame.file.clone());
+        if let Some(&id) = self.function_index.get(&key) {
+            return id;
+        }
+        let id = self.functions.len() as u64 + 1;
+        let mut m = Message::default();
+        m.varint(1, id);
+        let name = self.string(&key.0);
+        m.varint(2, name);
+        m.varint(3, name);
+        if let Some(file) = &key.1 {
+            m.varint(4, self.string(file));
+        }
+        self.functions.push(m);
+        self.function_index.insert(key, id);
+        id
+    }
+
+    fn location(&mut self, frame: &Frame) -> u64 {
+        if let Some(&id) = self.location_index.get(frame) {
+            return id;
+        }
+        let id = self.locations.len() as u64 + 1;
+        let mut line = Message::default();
+        line.varint(1, self.function(frame));
+        if let Some(n) = frame.line {
+            line.varint(2, n as u64);
+        }
+        let mut m = Message::default();
+        m.varint(1, id);
+        m.message(4, &line);
+        self.locations.push(m);
+        self.location_index.insert(frame.clone(), id);
+        id
+    }
+}
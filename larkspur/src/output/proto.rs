@@ -0,0 +1,38 @@
+/// 最小的 protobuf 编码器，只支持 pprof 和 OTLP profiles 用到的 varint 和 length-delimited 字段
+#[derive(Default)]
+pub struct Message {
+    pub buf: Vec<u8>,
+}
+
+impl Message {
+    pub fn raw_varint(&mut self, mut v: u64) {
+        while v >= 0x80 {
+            self.buf.push(v as u8 | 0x80);
+            v >>= 7;
+        }
+        self.buf.push(v as u8);
+    }
+
+    pub fn varint(&mut self, field: u32, v: u64) {
+        self.raw_varint((field as u64) << 3);
+        self.raw_varint(v);
+    }
+
+    pub fn bytes(&mut self, field: u32, data: &[u8]) {
+        self.raw_varint(((field as u64) << 3) | 2);
+        self.raw_varint(data.len() as u64);
+        self.buf.extend_from_slice(data);
+    }
+
+    pub fn message(&mut self, field: u32, m: &Message) {
+        self.bytes(field, &m.buf);
+    }
+
+    pub fn packed(&mut self, field: u32, values: &[u64]) {
+        let mut m = Message::default();
+        for &v in values {
+            m.raw_varint(v);
+        }
+        self.bytes(field, &m.buf);
+    }
+}
@@ -0,0 +1,215 @@
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+    io::{self, Write},
+};
+
+use crate::profile::{FrameKind, Profile, Unit};
+
+/// 终端可读的文本报告
+#[derive(Copy, Clone, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Report {
+    /// 从调用者到被调用者的调用树
+    Tree,
+    /// 按自身权重排序的函数表
+    Top,
+}
+
+pub fn write(profile: &Profile, report: Report, min_percent: f64, w: &mut dyn Write) -> io::Result<()> {
+    let total: u64 = profile.stacks.iter().map(|s| s.weight).sum();
+    writeln!(w, "total {}", format_weight(total, profile.unit))?;
+    if total > 0 {
+        match report {
+            Report::Tree => write_tree(profile, total, min_percent, w)?,
+            Report::Top => write_top(profile, total, min_percent, w)?,
+        }
+    }
+    w.flush()
+}
+
+#[derive(Default)]
+struct Node {
+    total: u64,
+    self_weight: u64,
+    children: HashMap<String, Node>,
+}
+
+fn write_tree(profile: &Profile, total: u64, min_percent: f64, w: &mut dyn Write) -> io::Result<()> {
+    let mut root = Node::default();
+    for stack in &profile.stacks {
+        let mut node = &mut root;
+        for frame in stack.frames() {
+            node = node.children.entry(frame).or_default();
+            node.total += stack.weight;
+        }
+        node.self_weight += stack.weight;
+    }
+
+    writeln!(w, "{:>8} {:>8}  call tree", "total%", "self%")?;
+    write_children(&root, total, min_percent, "", w)
+}
+
+fn write_children(node: &Node, total: u64, min_percent: f64, indent: &str, w: &mut dyn Write) -> io::Result<()> {
+    let mut children: Vec<_> =
+        node.children.iter().filter(|(_, c)| c.total > 0 && percent(c.total, total) >= min_percent).collect();
+    children.sort_by_key(|(name, c)| (Reverse(c.total), *name));
+
+    for (i, (name, child)) in children.iter().enumerate() {
+        let last = i + 1 == children.len();
+        writeln!(
+            w,
+            "{:>7.2}% {:>7.2}%  {indent}{}{name}",
+            percent(child.total, total),
+            percent(child.self_weight, total),
+            if last { "└─ " } else { "├─ " },
+        )?;
+        let indent = format!("{indent}{}", if last { "   " } else { "│  " });
+        write_children(child, total, min_percent, &indent, w)?;
+    }
+    Ok(())
+}
+
+fn write_top(profile: &Profile, total: u64, min_percent: f64, w: &mut dyn Write) -> io::Result<()> {
+    let mut funcs: HashMap<String, (u64, u64)> = HashMap::new();
+    for stack in &profile.stacks {
+        // 只看被采样的栈本身，不含 off-wake 的唤醒者和标签帧
+        let symbols: Vec<_> = stack
+            .symbols()
+            .into_iter()
+            .take_while(|f| f.kind != FrameKind::Label)
+            .collect();
+        let code: Vec<_> = symbols
+            .iter()
+            .filter(|f| matches!(f.kind, FrameKind::User | FrameKind::Kernel))
+            .collect();
+        // 没有任何代码帧时算在进程（或线程）名上
+        let frames = if code.is_empty() { symbols.iter().collect() } else { code };
+
+        if let Some(leaf) = frames.last() {
+            funcs.entry(leaf.folded()).or_default().0 += stack.weight;
+        }
+        // 递归调用只计一次
+        let mut seen = HashSet::new();
+        for frame in frames {
+            let name = frame.folded();
+            if seen.insert(name.clone()) {
+                funcs.entry(name).or_default().1 += stack.weight;
+            }
+        }
+    }
+
+    let mut funcs: Vec<_> = funcs
+        .into_iter()
+        .filter(|(_, (self_weight, _))| percent(*self_weight, total) >= min_percent && *self_weight > 0)
+        .collect();
+    funcs.sort_by(|(a_name, a), (b_name, b)| b.0.cmp(&a.0).then(b.1.cmp(&a.1)).then(a_name.cmp(b_name)));
+
+    writeln!(w, "{:>8} {:>8}  function", "self%", "total%")?;
+    for (name, (self_weight, total_weight)) in funcs {
+        writeln!(w, "{:>7.2}% {:>7.2}%  {name}", percent(self_weight, total), percent(total_weight, total))?;
+    }
+    Ok(())
+}
+
+fn percent(weight: u64, total: u64) -> f64 {
+    weight as f64 * 100.0 / total as f64
+}
+
+fn format_weight(weight: u64, unit: Unit) -> String {
+    match unit {
+        Unit::Samples => format!("{weight} samples"),
+        Unit::Nanoseconds => format!("{:.3} ms", weight as f64 / 1e6),
+        Unit::Bytes => format!("{weight} bytes"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profile::Stack;
+    use crate::symbolize::{kstack::KstackSymbol, ustack::UstackSymbol};
+
+    /// `user` 和 `kernel` 都从根到叶
+    fn stack(user: &[&str], kernel: &[&str], weight: u64) -> Stack {
+        Stack {
+            pid: 1,
+            comm: "app".into(),
+            thread: None,
+            kframes: kernel
+                .iter()
+                .rev()
+                .map(|f| KstackSymbol { offset: 0, function: Some(f.to_string()), module: None })
+                .collect(),
+            uframes: user
+                .iter()
+                .rev()
+                .map(|f| vec![UstackSymbol { function: Some(f.to_string()), ..UstackSymbol::unknown(0) }])
+                .collect(),
+            uaddrs: vec![0; user.len()],
+            weight,
+            label: None,
+            waker: None,
+        }
+    }
+
+    fn render(stacks: Vec<Stack>, report: Report, min_percent: f64) -> Vec<String> {
+        let profile = Profile { stacks, unit: Unit::Samples, timeline: Vec::new() };
+        let mut out = Vec::new();
+        write(&profile, report, min_percent, &mut out).unwrap();
+        String::from_utf8(out).unwrap().lines().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn tree() {
+        let lines = render(
+            vec![stack(&["main", "work"], &[], 3), stack(&["main"], &[], 1), stack(&["main", "rare"], &[], 1)],
+            Report::Tree,
+            25.0,
+        );
+        assert_eq!(
+            lines,
+            [
+                "total 5 samples",
+                "  total%    self%  call tree",
+                " 100.00%    0.00%  └─ app",
+                " 100.00%   20.00%     └─ main",
+                "  60.00%   60.00%        └─ work",
+            ]
+        );
+    }
+
+    #[test]
+    fn top_counts_recursion_once() {
+        let lines = render(
+            vec![
+                stack(&["main", "f", "f"], &[], 2),
+                stack(&["main", "g"], &["sys_read"], 1),
+                stack(&[], &[], 1),
+            ],
+            Report::Top,
+            0.0,
+        );
+        assert_eq!(
+            lines,
+            [
+                "total 4 samples",
+                "   self%   total%  function",
+                "  50.00%   50.00%  f",
+                "  25.00%   25.00%  app",
+                "  25.00%   25.00%  sys_read_[k]",
+            ]
+        );
+    }
+
+    #[test]
+    fn empty_profile() {
+        assert_eq!(render(Vec::new(), Report::Tree, 0.0), ["total 0 samples"]);
+        assert_eq!(render(Vec::new(), Report::Top, 0.0), ["total 0 samples"]);
+    }
+
+    #[test]
+    fn weights() {
+        assert_eq!(format_weight(1_500_000, Unit::Nanoseconds), "1.500 ms");
+        assert_eq!(format_weight(64, Unit::Bytes), "64 bytes");
+    }
+}
@@ -0,0 +1,78 @@
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+};
+
+use serde_json::json;
+
+use crate::profile::{Frame, Profile, Unit};
+
+/// 输出 speedscope 的 JSON 格式（https://www.speedscope.app/file-format-schema.json）。
+/// 有时间线时按时间顺序输出每个样本，否则每个栈输出一次
+pub fn write(profile: &Profile, w: &mut dyn Write) -> io::Result<()> {
+    let mut frames: Vec<&Frame> = Vec::new();
+    let mut index: HashMap<&Frame, usize> = HashMap::new();
+    let symbols: Vec<Vec<Frame>> = profile.stacks.iter().map(|s| s.symbols()).collect();
+    let stacks: Vec<Vec<usize>> = symbols
+        .iter()
+        .map(|stack| {
+            stack
+                .iter()
+                .map(|frame| {
+                    *index.entry(frame).or_insert_with(|| {
+                        frames.push(frame);
+                        frames.len() - 1
+                    })
+                })
+                .collect()
+        })
+        .collect();
+
+    let (samples, weights): (Vec<&Vec<usize>>, Vec<u64>) = if profile.timeline.is_empty() {
+        profile.stacks.iter().enumerate().map(|(i, s)| (&stacks[i], s.weight)).unzip()
+    } else {
+        let mut spans: Vec<_> = profile.timeline.iter().collect();
+        spans.sort_by_key(|s| s.start_ns);
+        spans.iter().map(|s| (&stacks[s.stack], s.weight)).unzip()
+    };
+    let total: u64 = weights.iter().sum();
+
+    let unit = match profile.unit {
+        Unit::Samples => "none",
+        Unit::Nanoseconds => "nanoseconds",
+        Unit::Bytes => "bytes",
+    };
+    let frames: Vec<_> = frames
+        .iter()
+        .map(|f| {
+            let mut frame = json!({ "name": f.folded() });
+            if let Some(file) = &f.file {
+                frame["file"] = json!(file);
+            }
+            if let Some(line) = f.line {
+                frame["line"] = json!(line);
+            }
+            frame
+        })
+        .collect();
+
+    let doc = json!({
+        "$schema": "https://www.speedscope.app/file-format-schema.json",
+        "exporter": concat!("larkspur ", env!("CARGO_PKG_VERSION")),
+        "name": "larkspur",
+        "activeProfileIndex": 0,
+        "shared": { "frames": frames },
+        "profiles": [{
+            "type": "sampled",
+            "name": "larkspur",
+            "unit": unit,
+            "startValue": 0,
+            "endValue": total,
+            "samples": samples,
+            "weights": weights,
+        }],
+    });
+    serde_json::to_writer(&mut *w, &doc)?;
+    writeln!(w)?;
+    w.flush()
+}
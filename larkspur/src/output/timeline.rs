@@ -0,0 +1,79 @@
+use std::{
+    collections::HashSet,
+    io::{self, Write},
+};
+
+use serde_json::json;
+
+use crate::profile::{Profile, Stack, thread_comm};
+
+/// 输出 Chrome trace-event JSON（可用 Perfetto 或 chrome://tracing 打开）：
+/// 每个线程一条轨道，事件名取最内层的用户帧，完整栈放在 `args.stack` 中
+pub fn write(profile: &Profile, w: &mut dyn Write) -> io::Result<()> {
+    let mut processes = HashSet::new();
+    let mut threads = HashSet::new();
+
+    write!(w, "{{\"displayTimeUnit\":\"ns\",\"traceEvents\":[")?;
+    let mut first = true;
+    let mut emit = |w: &mut dyn Write, event: serde_json::Value| -> io::Result<()> {
+        if !first {
+            write!(w, ",")?;
+        }
+        first = false;
+        writeln!(w)?;
+        serde_json::to_writer(&mut *w, &event)?;
+        Ok(())
+    };
+
+    for span in &profile.timeline {
+        let stack = &profile.stacks[span.stack];
+        if processes.insert(span.pid) {
+            emit(w, json!({
+                "ph": "M", "name": "process_name", "pid": span.pid,
+                "args": { "name": stack.comm },
+            }))?;
+        }
+        if threads.insert((span.pid, span.tid)) {
+            let name = match &stack.thread {
+                Some(thread) => thread.name.clone(),
+                None => Some(thread_comm(span.pid, span.tid))
+                    .filter(|n| !n.is_empty())
+                    .unwrap_or_else(|| stack.comm.clone()),
+            };
+            emit(w, json!({
+                "ph": "M", "name": "thread_name", "pid": span.pid, "tid": span.tid,
+                "args": { "name": name },
+            }))?;
+        }
+
+        let mut event = json!({
+            "name": leaf_name(stack),
+            "ts": span.start_ns as f64 / 1000.0,
+            "pid": span.pid,
+            "tid": span.tid,
+            "args": { "stack": stack.frames().join(";") },
+        });
+        if span.dur_ns > 0 {
+            event["ph"] = json!("X");
+            event["dur"] = json!(span.dur_ns as f64 / 1000.0);
+        } else {
+            event["ph"] = json!("i");
+            event["s"] = json!("t");
+        }
+        emit(w, event)?;
+    }
+
+    writeln!(w, "\n]}}")?;
+    w.flush()
+}
+
+// off-cpu 的内核叶端几乎总是 schedule，最内层的用户帧更有辨识度
+fn leaf_name(stack: &Stack) -> String {
+    if let Some(sym) = stack.uframes.first().and_then(|addr| addr.first()) {
+        return sym.name();
+    }
+    match stack.kframes.first() {
+        Some(sym) => format!("{}_[k]", sym.name()),
+        None => stack.comm.clone(),
+    }
+}
@@ -0,0 +1,311 @@
+use std::{cmp::Reverse, collections::HashMap, fs};
+
+use proc_maps::Pid;
+
+use crate::symbolize::{
+    kstack::{KStackResolver, KstackSymbol},
+    ustack::{ResolverCache, UstackSymbol},
+};
+
+/// 未符号化的聚合键，栈地址均为最内层在前
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct StackKey {
+    /// 进程 id，用于选择用户栈的符号解析器
+    pub pid: u32,
+    /// 按线程拆分时的线程 id，0 表示不拆分
+    pub tid: u32,
+    pub comm: String,
+    pub kaddrs: Vec<u64>,
+    pub uaddrs: Vec<u64>,
+    /// off-cpu 唤醒链中唤醒者的栈
+    pub waker: Option<Box<StackKey>>,
+}
+
+/// 在用户态按栈累加权重，采集结束后统一符号化
+#[derive(Default)]
+pub struct Aggregator {
+    index: HashMap<StackKey, usize>,
+    stacks: Vec<(StackKey, u64)>,
+    /// 采集开始时间（CLOCK_MONOTONIC 纳秒），时间窗口和时间线都相对它
+    start_ns: u64,
+    /// 只保留落在 [from, to) 内的样本，相对 `start_ns`
+    window: Option<(u64, u64)>,
+    timeline: Option<Vec<Span>>,
+}
+
+impl Aggregator {
+    pub fn new(start_ns: u64, window: Option<(u64, u64)>, timeline: bool) -> Self {
+        Aggregator {
+            start_ns,
+            window,
+            timeline: timeline.then(Vec::new),
+            ..Default::default()
+        }
+    }
+
+    pub fn add(&mut self, key: StackKey, weight: u64) {
+        self.insert(key, weight);
+    }
+
+    /// 带时间的样本：`ts_ns` 与 bpf_ktime_get_ns 同一时钟，窗口外的样本被丢弃
+    pub fn add_at(&mut self, key: StackKey, weight: u64, ts_ns: u64, dur_ns: u64, tid: u32, cpu: u32) {
+        let start_ns = ts_ns.saturating_sub(self.start_ns);
+        if self.window.is_some_and(|(from, to)| start_ns < from || start_ns >= to) {
+            return;
+        }
+        let pid = key.pid;
+        let stack = self.insert(key, weight);
+        if let Some(timeline) = &mut self.timeline {
+            timeline.push(Span { start_ns, dur_ns, weight, pid, tid, cpu, stack });
+        }
+    }
+
+    fn insert(&mut self, key: StackKey, weight: u64) -> usize {
+        if let Some(&i) = self.index.get(&key) {
+            self.stacks[i].1 += weight;
+            return i;
+        }
+        let i = self.stacks.len();
+        self.index.insert(key.clone(), i);
+        self.stacks.push((key, weight));
+        i
+    }
+
+    pub fn symbolize(self, k_resolver: &KStackResolver, u_resolvers: &mut ResolverCache) -> Profile {
+        let stacks = self
+            .stacks
+            .into_iter()
+            .map(|(key, weight)| key.symbolize(weight, k_resolver, u_resolvers))
+            .collect();
+        let mut profile = Profile {
+            stacks,
+            unit: Unit::default(),
+            timeline: self.timeline.unwrap_or_default(),
+        };
+        profile.sort();
+        profile
+    }
+}
+
+impl StackKey {
+    pub fn symbolize(self, weight: u64, k_resolver: &KStackResolver, u_resolvers: &mut ResolverCache) -> Stack {
+        // 按线程拆分时 comm 可能是内核给出的线程名，进程名以 /proc 为准
+        let (comm, thread) = match self.tid {
+            0 => (self.comm, None),
+            tid => {
+                let process = non_empty(process_comm(self.pid)).unwrap_or_else(|| self.comm.clone());
+                let name = non_empty(thread_comm(self.pid, tid)).unwrap_or(self.comm);
+                (process, Some(Thread { tid, name }))
+            }
+        };
+        Stack {
+            kframes: k_resolver.symbolize_stack(&self.kaddrs),
+            uframes: match u_resolvers.get(self.pid as Pid) {
+                Some(r) => r.symbolize_stack(&self.uaddrs),
+                None => self.uaddrs.iter().map(|&a| vec![UstackSymbol::unknown(a)]).collect(),
+            },
+            uaddrs: self.uaddrs,
+            pid: self.pid,
+            comm,
+            thread,
+            weight,
+            label: None,
+            waker: self.waker.map(|w| Box::new(w.symbolize(0, k_resolver, u_resolvers))),
+        }
+    }
+}
+
+pub struct Stack {
+    /// 进程 id，无法得知时为 0
+    pub pid: u32,
+    pub comm: String,
+    pub thread: Option<Thread>,
+    /// 内核栈，最内层在前
+    pub kframes: Vec<KstackSymbol>,
+    /// 用户栈，最内层在前；一个地址可能展开成多个内联帧
+    pub uframes: Vec<Vec<UstackSymbol>>,
+    /// 用户栈的运行时地址，与 `uframes` 一一对应
+    pub uaddrs: Vec<u64>,
+    pub weight: u64,
+    /// 附加在叶端的标签帧，如 wall 模式下的 `[on-cpu]` / `[off-cpu]`
+    pub label: Option<String>,
+    pub waker: Option<Box<Stack>>,
+}
+
+pub struct Thread {
+    pub tid: u32,
+    pub name: String,
+}
+
+impl Stack {
+    /// 从根到叶的帧：进程名、线程（按线程拆分时）、用户栈、内核栈。
+    /// 有唤醒者时按 offwaketime 的习惯，在 `--` 之后倒序接上唤醒者的栈
+    pub fn symbols(&self) -> Vec<Frame> {
+        let mut out = vec![Frame::synthetic(&self.comm, FrameKind::Process)];
+        if let Some(thread) = &self.thread {
+            out.push(Frame::synthetic(&format!("{}-{}", thread.name, thread.tid), FrameKind::Thread));
+        }
+        for addr in self.uframes.iter().rev() {
+            out.extend(addr.iter().map(|s| Frame {
+                name: s.name(),
+                kind: FrameKind::User,
+                file: s.file.clone(),
+                line: s.line,
+            }));
+        }
+        out.extend(self.kframes.iter().rev().map(|s| Frame {
+            name: s.name(),
+            kind: FrameKind::Kernel,
+            file: s.module.clone(),
+            line: None,
+        }));
+        if let Some(waker) = &self.waker {
+            out.push(Frame::synthetic("--", FrameKind::Label));
+            out.extend(waker.symbols().into_iter().rev());
+        }
+        out.extend(self.label.as_deref().map(|l| Frame::synthetic(l, FrameKind::Label)));
+        out
+    }
+
+    /// 纯文本格式使用的帧名，内核帧带 `_[k]` 后缀
+    pub fn frames(&self) -> Vec<String> {
+        self.symbols().iter().map(Frame::folded).collect()
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum FrameKind {
+    Process,
+    Thread,
+    User,
+    Kernel,
+    /// `--` 分隔符及 `[on-cpu]` 之类的标签
+    Label,
+}
+
+/// 与输出格式无关的帧
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Frame {
+    pub name: String,
+    pub kind: FrameKind,
+    /// 源文件；内核帧为所在模块
+    pub file: Option<String>,
+    pub line: Option<u32>,
+}
+
+impl Frame {
+    fn synthetic(name: &str, kind: FrameKind) -> Self {
+        Frame { name: name.to_string(), kind, file: None, line: None }
+    }
+
+    pub fn folded(&self) -> String {
+        match self.kind {
+            FrameKind::Kernel => format!("{}_[k]", self.name),
+            _ => self.name.clone(),
+        }
+    }
+}
+
+pub struct Profile {
+    pub stacks: Vec<Stack>,
+    pub unit: Unit,
+    /// 按时间记录的样本，未开启时间线时为空
+    pub timeline: Vec<Span>,
+}
+
+/// 权重的单位
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Unit {
+    #[default]
+    Samples,
+    Nanoseconds,
+    Bytes,
+}
+
+/// 时间线上的一段：一个 on-cpu 样本或一次阻塞，`dur_ns` 为 0 表示瞬时事件
+pub struct Span {
+    /// 相对采集开始的纳秒数
+    pub start_ns: u64,
+    pub dur_ns: u64,
+    /// 该样本计入栈的权重
+    pub weight: u64,
+    pub pid: u32,
+    pub tid: u32,
+    pub cpu: u32,
+    /// `Profile::stacks` 的下标
+    pub stack: usize,
+}
+
+impl Profile {
+    /// 权重乘以 `factor`，用于把采样次数换算成时间
+    pub fn scale(&mut self, factor: u64, unit: Unit) {
+        for stack in &mut self.stacks {
+            stack.weight *= factor;
+        }
+        for span in &mut self.timeline {
+            span.weight *= factor;
+        }
+        self.unit = unit;
+    }
+
+    pub fn label(&mut self, label: &str) {
+        for stack in &mut self.stacks {
+            stack.label = Some(label.to_string());
+        }
+    }
+
+    pub fn merge(mut self, other: Profile) -> Profile {
+        let offset = self.stacks.len();
+        self.stacks.extend(other.stacks);
+        self.timeline.extend(other.timeline.into_iter().map(|span| Span { stack: span.stack + offset, ..span }));
+        self.sort();
+        self
+    }
+
+    /// 按权重降序排列，同时更新时间线中的栈下标
+    fn sort(&mut self) {
+        let mut order: Vec<usize> = (0..self.stacks.len()).collect();
+        order.sort_by_key(|&i| Reverse(self.stacks[i].weight));
+        let mut new_index = vec![0; order.len()];
+        for (new, &old) in order.iter().enumerate() {
+            new_index[old] = new;
+        }
+        for span in &mut self.timeline {
+            span.stack = new_index[span.stack];
+        }
+
+        let mut stacks: Vec<Option<Stack>> = self.stacks.drain(..).map(Some).collect();
+        self.stacks = order.iter().map(|&i| stacks[i].take().unwrap()).collect();
+    }
+}
+
+/// 单个栈的阻塞时长分布，`stack.weight` 为总阻塞纳秒数
+pub struct Histogram {
+    pub stack: Stack,
+    /// 微秒级 log2 槽位，见 `larkspur_common::LatencyHist`
+    pub slots: Vec<u64>,
+}
+
+/// 把内核返回的定长 comm 转成字符串
+pub fn comm_to_string(comm: &[u8]) -> String {
+    let len = comm.iter().position(|&c| c == 0).unwrap_or(comm.len());
+    String::from_utf8_lossy(&comm[..len]).into_owned()
+}
+
+/// 读取 `/proc/<pid>/comm`，进程已退出时为空
+pub fn process_comm(pid: u32) -> String {
+    fs::read_to_string(format!("/proc/{pid}/comm"))
+        .map(|s| s.trim().to_string())
+        .unwrap_or_default()
+}
+
+/// 读取 `/proc/<pid>/task/<tid>/comm`，线程已退出时为空
+pub fn thread_comm(pid: u32, tid: u32) -> String {
+    fs::read_to_string(format!("/proc/{pid}/task/{tid}/comm"))
+        .map(|s| s.trim().to_string())
+        .unwrap_or_default()
+}
+
+fn non_empty(s: String) -> Option<String> {
+    (!s.is_empty()).then_some(s)
+}
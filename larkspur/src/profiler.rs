@@ -0,0 +1,104 @@
+use std::time::Duration;
+
+use anyhow::{Result, bail};
+use aya::programs::SamplePolicy;
+
+use crate::collector::{event::{self, Event}, on_cpu::{self, Continuous}, stream::SampleStream};
+use crate::profile::Profile;
+
+const RING_BUF_KIB: u32 = 256;
+
+/// 持续运行的 on-cpu 采样，由 [`OnCpuProfiler::builder`] 创建。
+/// 样本在内核中按栈计数，每次取走上次以来的部分；drop 时卸载 eBPF 程序
+pub struct OnCpuProfiler {
+    session: Continuous,
+}
+
+impl OnCpuProfiler {
+    pub fn builder() -> OnCpuProfilerBuilder {
+        OnCpuProfilerBuilder::default()
+    }
+
+    /// 取走上次调用（或启动）以来的样本并符号化
+    pub fn take(&mut self) -> Result<Profile> {
+        self.session.take()
+    }
+
+    /// 再采集 `period` 后取走
+    pub async fn profile(&mut self, period: Duration) -> Result<Profile> {
+        tokio::time::sleep(period).await;
+        self.take()
+    }
+}
+
+pub struct OnCpuProfilerBuilder {
+    pid: Option<u32>,
+    event: Event,
+    policy: SamplePolicy,
+    threads: bool,
+}
+
+impl Default for OnCpuProfilerBuilder {
+    fn default() -> Self {
+        OnCpuProfilerBuilder {
+            pid: None,
+            event: Event::CpuClock,
+            policy: SamplePolicy::Frequency(event::DEFAULT_FREQUENCY),
+            threads: false,
+        }
+    }
+}
+
+impl OnCpuProfilerBuilder {
+    /// 只采集该进程，默认所有进程
+    pub fn pid(mut self, pid: u32) -> Self {
+        self.pid = Some(pid);
+        self
+    }
+
+    /// 每秒每个 CPU 采样次数，默认 99
+    pub fn frequency(mut self, frequency: u64) -> Self {
+        self.policy = SamplePolicy::Frequency(frequency);
+        self
+    }
+
+    /// 改为每发生 `period` 次事件采样一次
+    pub fn period(mut self, period: u64) -> Self {
+        self.policy = SamplePolicy::Period(period);
+        self
+    }
+
+    /// 采样事件，默认 cpu-clock
+    pub fn event(mut self, event: Event) -> Self {
+        self.event = event;
+        self
+    }
+
+    /// 按线程拆分
+    pub fn threads(mut self, threads: bool) -> Self {
+        self.threads = threads;
+        self
+    }
+
+    /// 加载并挂载 eBPF 程序，需要 root 或 CAP_BPF + CAP_PERFMON
+    pub fn start(self) -> Result<OnCpuProfiler> {
+        self.check()?;
+        Ok(OnCpuProfiler {
+            session: Continuous::start(self.pid, self.event, self.policy, self.threads)?,
+        })
+    }
+
+    /// 不聚合，改为逐个样本推送；`symbolize` 为 true 时附带符号化的帧。
+    /// 需要在 tokio 运行时中调用
+    pub fn stream(self, symbolize: bool) -> Result<SampleStream> {
+        self.check()?;
+        on_cpu::stream(self.pid, self.event, self.policy, symbolize, RING_BUF_KIB)
+    }
+
+    fn check(&self) -> Result<()> {
+        if matches!(self.policy, SamplePolicy::Frequency(0) | SamplePolicy::Period(0)) {
+            bail!("frequency and period must be greater than zero");
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,254 @@
+use std::{collections::HashMap, convert::Infallible, fmt::Display, net::SocketAddr, str::FromStr};
+
+use anyhow::{Result, anyhow, bail};
+use aya::programs::SamplePolicy;
+use clap::ValueEnum;
+use http_body_util::Full;
+use hyper::{Method, Request, Response, StatusCode, body::{Bytes, Incoming}, header, server::conn::http1, service::service_fn};
+use hyper_util::rt::TokioIo;
+use log::{info, warn};
+use tokio::net::TcpListener;
+
+use crate::collector::{self, SampleOpts, event::Event, off_cpu::{BlockState, Filter}, shutdown_signal};
+use crate::metrics;
+use crate::output::{self, Format};
+use crate::profile::Profile;
+
+const RING_BUF_KIB: u32 = 256;
+
+const INDEX: &str = "\
+larkspur profiling endpoints
+
+/debug/pprof/profile?pid=N&seconds=30&frequency=99&event=cpu-clock&threads=0&format=pprof
+    on-cpu profile
+/debug/pprof/offcpu?pid=N&seconds=30&state=all&min_block_us=0&threads=0&format=pprof
+    off-cpu profile, weights in nanoseconds
+/debug/pprof/flamegraph.svg?pid=N&seconds=30&mode=cpu&frequency=99&threads=0
+    flame graph, mode is cpu, offcpu or wall
+/metrics
+    Prometheus metrics about the profiler itself and the hottest processes
+
+format is one of folded, speedscope, firefox, pprof, svg, json, jsonl
+";
+
+/// HTTP 服务配置
+#[derive(Copy, Clone, Debug)]
+pub struct Config {
+    pub listen: SocketAddr,
+    /// 单次请求允许的最长采集时间（秒）
+    pub max_seconds: u64,
+    /// 为 false 时只提供 `/metrics`
+    pub profiling: bool,
+}
+
+/// 类似 Go net/http/pprof 的按需采集服务，每个请求独立加载并运行一次采集，
+/// 直到收到 SIGINT/SIGTERM
+pub async fn run(config: Config) -> Result<()> {
+    let listener = TcpListener::bind(config.listen).await?;
+    info!("listening on http://{}/debug/pprof/", listener.local_addr()?);
+    serve(listener, config).await
+}
+
+/// 在已绑定的端口上提供服务，供需要先确认端口可用的调用方使用
+pub async fn serve(listener: TcpListener, config: Config) -> Result<()> {
+    let stop = shutdown_signal();
+    tokio::pin!(stop);
+    loop {
+        let (stream, peer) = tokio::select! {
+            r = listener.accept() => r?,
+            r = &mut stop => return r,
+        };
+        tokio::spawn(async move {
+            let service = service_fn(move |req| handle(req, config));
+            if let Err(e) = http1::Builder::new().serve_connection(TokioIo::new(stream), service).await {
+                warn!("connection from {peer} failed: {e}");
+            }
+        });
+    }
+}
+
+/// 参数错误返回 400，采集失败返回 500
+enum Error {
+    BadRequest(anyhow::Error),
+    Collect(anyhow::Error),
+}
+
+async fn handle(req: Request<Incoming>, config: Config) -> Result<Response<Full<Bytes>>, Infallible> {
+    info!("{} {}", req.method(), req.uri());
+    if req.method() != Method::GET {
+        return Ok(text(StatusCode::METHOD_NOT_ALLOWED, "only GET is supported\n".to_string()));
+    }
+    if req.uri().path() == "/metrics" {
+        let resp = Response::builder()
+            .header(header::CONTENT_TYPE, "text/plain; version=0.0.4; charset=utf-8")
+            .body(Full::new(Bytes::from(metrics::render())))
+            .unwrap();
+        return Ok(resp);
+    }
+    if !config.profiling {
+        return Ok(text(StatusCode::NOT_FOUND, "not found\n".to_string()));
+    }
+
+    let query = Query::parse(req.uri().query().unwrap_or(""));
+    let result = match req.uri().path() {
+        "/" | "/debug/pprof" | "/debug/pprof/" => return Ok(text(StatusCode::OK, INDEX.to_string())),
+        "/debug/pprof/profile" => on_cpu(&query, config).await,
+        "/debug/pprof/offcpu" => off_cpu(&query, config).await,
+        "/debug/pprof/flamegraph.svg" => flamegraph(&query, config).await,
+        _ => return Ok(text(StatusCode::NOT_FOUND, "not found\n".to_string())),
+    };
+    Ok(match result {
+        Ok(resp) => resp,
+        Err(Error::BadRequest(e)) => text(StatusCode::BAD_REQUEST, format!("{e:#}\n")),
+        Err(Error::Collect(e)) => {
+            warn!("{} failed: {e:#}", req.uri());
+            text(StatusCode::INTERNAL_SERVER_ERROR, format!("{e:#}\n"))
+        }
+    })
+}
+
+async fn on_cpu(query: &Query, config: Config) -> Result<Response<Full<Bytes>>, Error> {
+    let (pid, seconds, format) = common(query, config, Format::Pprof).map_err(Error::BadRequest)?;
+    let (frequency, event, threads) = (|| {
+        Ok::<_, anyhow::Error>((query.get("frequency", 99u64)?, query.get("event", Event::CpuClock)?, query.flag("threads")?))
+    })()
+    .map_err(Error::BadRequest)?;
+    if frequency == 0 {
+        return Err(Error::BadRequest(anyhow!("frequency must be greater than zero")));
+    }
+
+    let opts = SampleOpts { threads, ..Default::default() };
+    let profile = collector::on_cpu::run(pid, seconds, event, SamplePolicy::Frequency(frequency), true, opts, RING_BUF_KIB)
+        .await
+        .map_err(Error::Collect)?;
+    render(&profile, format)
+}
+
+async fn off_cpu(query: &Query, config: Config) -> Result<Response<Full<Bytes>>, Error> {
+    let (pid, seconds, format) = common(query, config, Format::Pprof).map_err(Error::BadRequest)?;
+    let (filter, threads) = (|| {
+        let filter = Filter {
+            state: query.value_enum("state", BlockState::All)?,
+            min_block_us: query.optional("min_block_us")?,
+            max_block_us: query.optional("max_block_us")?,
+        };
+        Ok::<_, anyhow::Error>((filter, query.flag("threads")?))
+    })()
+    .map_err(Error::BadRequest)?;
+
+    let opts = SampleOpts { threads, ..Default::default() };
+    let profile = collector::off_cpu::run(pid, seconds, filter, false, opts, RING_BUF_KIB)
+        .await
+        .map_err(Error::Collect)?;
+    render(&profile, format)
+}
+
+async fn flamegraph(query: &Query, config: Config) -> Result<Response<Full<Bytes>>, Error> {
+    let (pid, seconds, _) = common(query, config, Format::Svg).map_err(Error::BadRequest)?;
+    let (mode, frequency, threads) = (|| {
+        Ok::<_, anyhow::Error>((query.get("mode", "cpu".to_string())?, query.get("frequency", 99u64)?, query.flag("threads")?))
+    })()
+    .map_err(Error::BadRequest)?;
+
+    let opts = SampleOpts { threads, ..Default::default() };
+    let profile = match mode.as_str() {
+        "cpu" => {
+            if frequency == 0 {
+                return Err(Error::BadRequest(anyhow!("frequency must be greater than zero")));
+            }
+            collector::on_cpu::run(pid, seconds, Event::CpuClock, SamplePolicy::Frequency(frequency), true, opts, RING_BUF_KIB).await
+        }
+        "offcpu" => collector::off_cpu::run(pid, seconds, Filter::default(), false, opts, RING_BUF_KIB).await,
+        "wall" => collector::wall::run(pid, seconds, frequency, threads, RING_BUF_KIB).await,
+        _ => return Err(Error::BadRequest(anyhow!("unknown mode `{mode}`, expected cpu, offcpu or wall"))),
+    }
+    .map_err(Error::Collect)?;
+    render(&profile, Format::Svg)
+}
+
+/// 所有采集端点共有的参数：pid（必填）、seconds 和 format
+fn common(query: &Query, config: Config, format: Format) -> Result<(u32, u64, Format)> {
+    let pid = query.optional("pid")?.ok_or_else(|| anyhow!("missing `pid` parameter"))?;
+    let seconds = query.get("seconds", 30)?;
+    if seconds == 0 || seconds > config.max_seconds {
+        bail!("seconds must be between 1 and {}", config.max_seconds);
+    }
+    Ok((pid, seconds, query.value_enum("format", format)?))
+}
+
+fn render(profile: &Profile, format: Format) -> Result<Response<Full<Bytes>>, Error> {
+    let mut body = Vec::new();
+    output::write(profile, format, &mut body).map_err(|e| Error::Collect(e.into()))?;
+    let content_type = match format {
+        Format::Folded => "text/plain; charset=utf-8",
+        Format::Speedscope | Format::Firefox | Format::Json => "application/json",
+        Format::Jsonl => "application/x-ndjson",
+        Format::Pprof => "application/octet-stream",
+        Format::Svg => "image/svg+xml",
+    };
+    let mut resp = Response::builder().header(header::CONTENT_TYPE, content_type);
+    // 与 net/http/pprof 一致，方便 curl -O / go tool pprof 直接保存
+    if format == Format::Pprof {
+        resp = resp.header(header::CONTENT_DISPOSITION, "attachment; filename=\"profile\"");
+    }
+    Ok(resp.body(Full::new(Bytes::from(body))).unwrap())
+}
+
+fn text(status: StatusCode, body: String) -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body(Full::new(Bytes::from(body)))
+        .unwrap()
+}
+
+/// URL 查询参数，值只有数字和枚举名，不做百分号解码
+struct Query(HashMap<String, String>);
+
+impl Query {
+    fn parse(query: &str) -> Self {
+        Query(
+            query
+                .split('&')
+                .filter(|kv| !kv.is_empty())
+                .map(|kv| match kv.split_once('=') {
+                    Some((k, v)) => (k.to_string(), v.to_string()),
+                    None => (kv.to_string(), String::new()),
+                })
+                .collect(),
+        )
+    }
+
+    fn optional<T: FromStr>(&self, name: &str) -> Result<Option<T>>
+    where
+        T::Err: Display,
+    {
+        self.0
+            .get(name)
+            .map(|v| v.parse().map_err(|e| anyhow!("invalid `{name}`: {e}")))
+            .transpose()
+    }
+
+    fn get<T: FromStr>(&self, name: &str, default: T) -> Result<T>
+    where
+        T::Err: Display,
+    {
+        Ok(self.optional(name)?.unwrap_or(default))
+    }
+
+    fn value_enum<T: ValueEnum>(&self, name: &str, default: T) -> Result<T> {
+        match self.0.get(name) {
+            Some(v) => T::from_str(v, true).map_err(|e| anyhow!("invalid `{name}`: {e}")),
+            None => Ok(default),
+        }
+    }
+
+    /// `threads`、`threads=1` 和 `threads=true` 都表示开启
+    fn flag(&self, name: &str) -> Result<bool> {
+        match self.0.get(name).map(String::as_str) {
+            None | Some("0") | Some("false") => Ok(false),
+            Some("") | Some("1") | Some("true") => Ok(true),
+            Some(v) => bail!("invalid `{name}`: {v}"),
+        }
+    }
+}
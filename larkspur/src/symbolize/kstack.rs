@@ -15,6 +15,11 @@ impl KstackSymbol {
             _ => format!("0x{:x}", self.offset),
         }
     }
+
+    /// 函数名，无法解析时为地址
+    pub fn name(&self) -> String {
+        self.function.clone().unwrap_or_else(|| format!("0x{:x}", self.offset))
+    }
 }
 
 pub struct KStackResolver {
@@ -1,6 +1,8 @@
 use proc_maps::MapRange;
+use std::collections::HashMap;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
 
 use anyhow::Result;
 use goblin::elf::{Elf, note::NT_GNU_BUILD_ID};
@@ -8,6 +10,8 @@ use log::{debug};
 use proc_maps::{get_process_maps, Pid};
 use blazesym::{symbolize, symbolize::Symbolizer};
 
+use crate::metrics;
+
 thread_local! {
     static SYMBOLIZER: Symbolizer = Symbolizer::new();
 }
@@ -21,6 +25,17 @@ pub struct UstackSymbol {
 }
 
 impl UstackSymbol {
+    /// 无法符号化的地址
+    pub fn unknown(offset: u64) -> Self {
+        UstackSymbol {
+            offset,
+            function: None,
+            file: None,
+            line: None,
+            inline: false
+        }
+    }
+
     pub fn to_string_lossy(&self) -> String {
         match (self.function.as_ref(), self.file.as_ref(), self.line) {
             (Some(f), Some(file), Some(line)) => format!("{} at {}:{}", f, file, line),
@@ -28,6 +43,11 @@ impl UstackSymbol {
             _ => format!("0x{:x}", self.offset),
         }
     }
+
+    /// 函数名，无法解析时为地址
+    pub fn name(&self) -> String {
+        self.function.clone().unwrap_or_else(|| format!("0x{:x}", self.offset))
+    }
 }
 
 pub struct Resolver{
@@ -60,22 +80,10 @@ impl Resolver {
 
     pub fn symbolize_addr(&self, addr: u64) -> Vec<UstackSymbol> {
         let Some((elf_path, offset)) = self.runtime_addr_to_offset(addr) else {
-            return vec![UstackSymbol {
-                offset: addr,
-                function: None,
-                file: None,
-                line: None,
-                inline: false
-            }];
+            return vec![UstackSymbol::unknown(addr)];
         };
 
-        symbolize_elf(&elf_path, offset).unwrap_or_else(|_| vec![UstackSymbol {
-            offset,
-            function: None,
-            file: None,
-            line: None,
-            inline: false
-        }])
+        symbolize_elf(&elf_path, offset).unwrap_or_else(|_| vec![UstackSymbol::unknown(offset)])
     }
 
     pub fn symbolize_stack(&self, addrs: &[u64]) -> Vec<Vec<UstackSymbol>> {
@@ -86,6 +94,39 @@ impl Resolver {
     }
 }
 
+/// 按进程缓存 Resolver，用于全系统采样时符号化多个进程的栈
+#[derive(Default)]
+pub struct ResolverCache {
+    resolvers: HashMap<Pid, Option<Resolver>>,
+}
+
+impl ResolverCache {
+    /// 预先读取目标进程的内存映射，进程不存在时报错
+    pub fn with_pid(pid: Pid) -> anyhow::Result<Self> {
+        let mut cache = Self::default();
+        cache.resolvers.insert(pid, Some(Resolver::new(pid)?));
+        Ok(cache)
+    }
+
+    /// 丢弃已退出进程的 Resolver，以及之前读取内存映射失败的记录（下次再试）
+    pub fn retain_live(&mut self) {
+        self.resolvers
+            .retain(|pid, r| r.is_some() && Path::new(&format!("/proc/{pid}")).exists());
+    }
+
+    /// 进程已退出、无法读取内存映射时返回 None
+    pub fn get(&mut self, pid: Pid) -> Option<&Resolver> {
+        metrics::resolver_lookup(self.resolvers.contains_key(&pid));
+        self.resolvers
+            .entry(pid)
+            .or_insert_with(|| {
+                Resolver::new(pid)
+                    .inspect_err(|e| debug!("no maps for pid {}: {}", pid, e))
+                    .ok()
+            })
+            .as_ref()
+    }
+}
 
 pub(crate) fn read_build_id(path: &Path) -> Result<Option<String>> {
     let buf = fs::read(path)?;
@@ -112,21 +153,35 @@ pub(crate) fn read_build_id(path: &Path) -> Result<Option<String>> {
     Ok(None)
 }
 
+/// 额外的调试符号目录，布局与 /usr/lib/debug 相同，先于它查找
+static DEBUG_DIRS: OnceLock<Vec<PathBuf>> = OnceLock::new();
+
+/// 设置额外的调试符号目录，只在第一次调用时生效，应在符号化之前调用
+pub fn set_debug_dirs(dirs: Vec<PathBuf>) {
+    let _ = DEBUG_DIRS.set(dirs);
+}
+
 fn find_debug_path(original: &str) -> Option<String> {
-    if let Ok(Some(build_id)) = read_build_id(Path::new(original)) {
-        let (dir, file) = build_id.split_at(2);
-        let p = format!("/usr/lib/debug/.build-id/{}/{}.debug", dir, &file);
-        if Path::new(&p).is_file() {
-            debug!("found debug via build-id: {}", p);
-            return Some(p);
+    let extra = DEBUG_DIRS.get().map(Vec::as_slice).unwrap_or_default();
+    let dirs = extra.iter().map(|d| d.to_string_lossy().into_owned()).chain(["/usr/lib/debug".to_string()]);
+    let build_id = read_build_id(Path::new(original)).ok().flatten();
+
+    for root in dirs {
+        if let Some(build_id) = &build_id {
+            let (dir, file) = build_id.split_at(2);
+            let p = format!("{}/.build-id/{}/{}.debug", root, dir, &file);
+            if Path::new(&p).is_file() {
+                debug!("found debug via build-id: {}", p);
+                return Some(p);
+            }
         }
-    }
 
-    if original.starts_with('/') {
-        let p = format!("/usr/lib/debug{}", original);
-        if Path::new(&p).is_file() {
-            debug!("found debug via path: {}", p);
-            return Some(p);
+        if original.starts_with('/') {
+            let p = format!("{}{}", root, original);
+            if Path::new(&p).is_file() {
+                debug!("found debug via path: {}", p);
+                return Some(p);
+            }
         }
     }
 
@@ -148,13 +203,7 @@ fn symbolize_elf(binary_path: &str, offset: u64) ->Result<Vec<UstackSymbol>> {
 
 
 
-    Ok(vec![UstackSymbol {
-            offset,
-            function: None,
-            file: None,
-            line: None,
-            inline: false
-    }])
+    Ok(vec![UstackSymbol::unknown(offset)])
 }
 
 
@@ -0,0 +1,460 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result, anyhow, bail};
+use log::{info, warn};
+use serde_json::json;
+
+use crate::output::pprof;
+use crate::profile::Profile;
+
+pub mod otlp;
+
+const SPOOL_SUFFIX: &str = ".spool";
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+#[cfg(not(test))]
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+#[cfg(test)]
+const INITIAL_BACKOFF: Duration = Duration::from_millis(10);
+
+/// 上传协议
+#[derive(Copy, Clone, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Protocol {
+    /// Pyroscope / Grafana 的 `/ingest`，上传 pprof
+    Pyroscope,
+    /// OTLP profiles（实验性，`/v1development/profiles`）
+    Otlp,
+}
+
+/// 一个 profile 覆盖的时间段
+pub struct Window {
+    pub start: SystemTime,
+    pub end: SystemTime,
+    /// 采样频率，0 表示未知
+    pub sample_rate: u64,
+}
+
+impl Window {
+    fn start_ns(&self) -> u64 {
+        self.start.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64
+    }
+
+    fn duration_ns(&self) -> u64 {
+        self.end.duration_since(self.start).unwrap_or_default().as_nanos() as u64
+    }
+
+    fn secs(t: SystemTime) -> u64 {
+        t.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+    }
+}
+
+/// 把 profile 推送到远端；失败的请求写入 spool 目录，下次上传前按时间顺序补发
+pub struct Uploader {
+    pub protocol: Protocol,
+    /// 服务端根地址，如 `http://pyroscope:4040`
+    pub url: String,
+    pub service: String,
+    pub host: String,
+    /// 附加在每个 profile 上的标签
+    pub labels: Vec<(String, String)>,
+    /// 失败后的重试次数
+    pub retries: u32,
+    pub spool_dir: Option<PathBuf>,
+    /// spool 中最多保留的请求数，超出时丢弃最旧的
+    pub spool_max: usize,
+}
+
+/// 一次待发送的 HTTP 请求，spool 中保存的就是它
+#[derive(Debug, PartialEq)]
+struct Request {
+    url: String,
+    query: Vec<(String, String)>,
+    content_type: String,
+    body: Vec<u8>,
+}
+
+enum SendError {
+    /// 服务端拒收（4xx），重试也不会成功
+    Rejected(String),
+    /// 连接失败、5xx 或 429，可以稍后重试
+    Unavailable(String),
+}
+
+impl Uploader {
+    pub async fn upload(&self, profile: &Profile, window: &Window) -> Result<()> {
+        self.flush_spool().await;
+
+        let request = self.request(profile, window)?;
+        let mut delay = INITIAL_BACKOFF;
+        let mut attempt = 0;
+        let reason = loop {
+            match send(&request).await {
+                Ok(()) => return Ok(()),
+                Err(SendError::Rejected(e)) => {
+                    warn!("{} rejected the profile, dropping it: {e}", request.url);
+                    return Ok(());
+                }
+                Err(SendError::Unavailable(e)) if attempt >= self.retries => break e,
+                Err(SendError::Unavailable(e)) => {
+                    warn!("upload to {} failed, retrying in {delay:?}: {e}", request.url);
+                    tokio::time::sleep(delay).await;
+                    delay = (delay * 2).min(MAX_BACKOFF);
+                    attempt += 1;
+                }
+            }
+        };
+
+        if let Some(dir) = &self.spool_dir {
+            spool(dir, &request, self.spool_max)?;
+            bail!("upload to {} failed, spooled to {}: {reason}", request.url, dir.display());
+        }
+        bail!("upload to {} failed: {reason}", request.url)
+    }
+
+    /// 不尝试发送，直接写入 spool，留到下次上传时补发
+    pub fn defer(&self, profile: &Profile, window: &Window) -> Result<()> {
+        let Some(dir) = &self.spool_dir else {
+            bail!("no spool directory");
+        };
+        spool(dir, &self.request(profile, window)?, self.spool_max)
+    }
+
+    fn request(&self, profile: &Profile, window: &Window) -> Result<Request> {
+        let url = self.url.trim_end_matches('/');
+        Ok(match self.protocol {
+            Protocol::Pyroscope => {
+                let mut labels = vec![format!("service_name={}", self.service), format!("host={}", self.host)];
+                labels.extend(self.labels.iter().map(|(k, v)| format!("{k}={v}")));
+                let mut body = Vec::new();
+                pprof::write(profile, &mut body)?;
+                Request {
+                    url: format!("{url}/ingest"),
+                    query: vec![
+                        ("name".into(), format!("{}.cpu{{{}}}", self.service, labels.join(","))),
+                        ("from".into(), Window::secs(window.start).to_string()),
+                        ("until".into(), Window::secs(window.end).to_string()),
+                        ("format".into(), "pprof".into()),
+                        ("sampleRate".into(), window.sample_rate.to_string()),
+                        ("spyName".into(), "larkspur".into()),
+                    ],
+                    content_type: "application/octet-stream".into(),
+                    body,
+                }
+            }
+            Protocol::Otlp => {
+                let mut resource = vec![
+                    ("service.name".to_string(), self.service.clone()),
+                    ("host.name".to_string(), self.host.clone()),
+                ];
+                resource.extend(self.labels.iter().cloned());
+                Request {
+                    url: format!("{url}/v1development/profiles"),
+                    query: Vec::new(),
+                    content_type: "application/x-protobuf".into(),
+                    body: otlp::encode(profile, window, &resource),
+                }
+            }
+        })
+    }
+
+    /// 按时间顺序补发 spool 中的请求，遇到第一个仍无法送达的就停下
+    async fn flush_spool(&self) {
+        let Some(dir) = &self.spool_dir else { return };
+        let paths = match spooled(dir) {
+            Ok(paths) => paths,
+            Err(e) => {
+                warn!("failed to read spool {}: {e}", dir.display());
+                return;
+            }
+        };
+        for path in paths {
+            let request = match fs::read(&path).map_err(anyhow::Error::from).and_then(|data| decode(&data)) {
+                Ok(request) => request,
+                Err(e) => {
+                    warn!("dropping unreadable spool file {}: {e}", path.display());
+                    let _ = fs::remove_file(&path);
+                    continue;
+                }
+            };
+            match send(&request).await {
+                Ok(()) => info!("sent spooled profile {}", path.display()),
+                Err(SendError::Rejected(e)) => warn!("{} rejected spooled profile {}: {e}", request.url, path.display()),
+                Err(SendError::Unavailable(_)) => return,
+            }
+            let _ = fs::remove_file(&path);
+        }
+    }
+}
+
+/// 读取 `/proc/sys/kernel/hostname`
+pub fn hostname() -> String {
+    fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|s| s.trim().to_string())
+        .unwrap_or_else(|_| "unknown".to_string())
+}
+
+/// 解析 `key=value` 形式的标签
+pub fn parse_label(s: &str) -> Result<(String, String)> {
+    match s.split_once('=') {
+        Some((k, v)) if !k.is_empty() => Ok((k.to_string(), v.to_string())),
+        _ => Err(anyhow!("invalid label `{s}`, expected key=value")),
+    }
+}
+
+async fn send(request: &Request) -> Result<(), SendError> {
+    let mut req = ureq::post(&request.url)
+        .timeout(Duration::from_secs(30))
+        .set("Content-Type", &request.content_type)
+        .set("User-Agent", concat!("larkspur/", env!("CARGO_PKG_VERSION")));
+    for (k, v) in &request.query {
+        req = req.query(k, v);
+    }
+    let body = request.body.clone();
+    tokio::task::spawn_blocking(move || match req.send_bytes(&body) {
+        Ok(_) => Ok(()),
+        Err(ureq::Error::Status(code, resp)) => {
+            let msg = format!("HTTP {code}: {}", resp.into_string().unwrap_or_default().trim());
+            if code == 429 || code >= 500 {
+                Err(SendError::Unavailable(msg))
+            } else {
+                Err(SendError::Rejected(msg))
+            }
+        }
+        Err(ureq::Error::Transport(e)) => Err(SendError::Unavailable(e.to_string())),
+    })
+    .await
+    .unwrap_or_else(|e| Err(SendError::Unavailable(e.to_string())))
+}
+
+/// 写入 spool 目录，超出 `max` 个时删除最旧的
+fn spool(dir: &Path, request: &Request, max: usize) -> Result<()> {
+    fs::create_dir_all(dir).with_context(|| format!("failed to create {}", dir.display()))?;
+    let data = encode(request)?;
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+    let path = dir.join(format!("{nanos:024}{SPOOL_SUFFIX}"));
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, &data)?;
+    fs::rename(&tmp, &path)?;
+
+    let paths = spooled(dir)?;
+    for old in paths.iter().take(paths.len().saturating_sub(max)) {
+        warn!("spool is full, dropping {}", old.display());
+        fs::remove_file(old)?;
+    }
+    Ok(())
+}
+
+/// spool 中的文件，最旧的在前
+fn spooled(dir: &Path) -> Result<Vec<PathBuf>> {
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.to_string_lossy().ends_with(SPOOL_SUFFIX) {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+/// spool 文件：一行 JSON 头，之后是原样的请求体
+fn encode(request: &Request) -> Result<Vec<u8>> {
+    let header = json!({
+        "url": request.url,
+        "query": request.query,
+        "content_type": request.content_type,
+    });
+    let mut data = serde_json::to_vec(&header)?;
+    data.push(b'\n');
+    data.extend_from_slice(&request.body);
+    Ok(data)
+}
+
+fn decode(data: &[u8]) -> Result<Request> {
+    let split = data.iter().position(|&b| b == b'\n').ok_or_else(|| anyhow!("missing header"))?;
+    let header: serde_json::Value = serde_json::from_slice(&data[..split])?;
+    let field = |name: &str| header[name].as_str().map(str::to_string).ok_or_else(|| anyhow!("missing `{name}`"));
+    let query = serde_json::from_value(header["query"].clone())?;
+    Ok(Request {
+        url: field("url")?,
+        query,
+        content_type: field("content_type")?,
+        body: data[split + 1..].to_vec(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::TcpListener,
+        sync::{Arc, Mutex},
+        thread,
+    };
+
+    use super::*;
+    use crate::profile::Unit;
+
+    /// 按顺序返回 `statuses` 中的状态码（用完后一直返回最后一个），记录收到的请求体
+    fn stub(statuses: &[u16]) -> (String, Arc<Mutex<Vec<Vec<u8>>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let bodies = Arc::new(Mutex::new(Vec::new()));
+        let (statuses, seen) = (statuses.to_vec(), bodies.clone());
+        thread::spawn(move || {
+            for (i, stream) in listener.incoming().enumerate() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(&stream);
+                let mut len = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':')
+                        && name.eq_ignore_ascii_case("content-length")
+                    {
+                        len = value.trim().parse().unwrap();
+                    }
+                }
+                let mut body = vec![0; len];
+                reader.read_exact(&mut body).unwrap();
+                seen.lock().unwrap().push(body);
+                let status = statuses[i.min(statuses.len() - 1)];
+                write!(stream, "HTTP/1.1 {status} X\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").unwrap();
+            }
+        });
+        (url, bodies)
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("larkspur-upload-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn uploader(url: String, retries: u32, spool_dir: Option<PathBuf>) -> Uploader {
+        Uploader {
+            protocol: Protocol::Pyroscope,
+            url,
+            service: "test".into(),
+            host: "host".into(),
+            labels: Vec::new(),
+            retries,
+            spool_dir,
+            spool_max: 10,
+        }
+    }
+
+    fn profile() -> Profile {
+        Profile { stacks: Vec::new(), unit: Unit::default(), timeline: Vec::new() }
+    }
+
+    fn window() -> Window {
+        Window { start: UNIX_EPOCH, end: UNIX_EPOCH + Duration::from_secs(60), sample_rate: 99 }
+    }
+
+    fn request(url: &str, body: &[u8]) -> Request {
+        Request {
+            url: format!("{url}/ingest"),
+            query: vec![("name".into(), "test.cpu{}".into())],
+            content_type: "application/octet-stream".into(),
+            body: body.to_vec(),
+        }
+    }
+
+    #[test]
+    fn spool_round_trip() {
+        let request = request("http://localhost", b"\x00\x01\n\xffbody");
+        assert_eq!(decode(&encode(&request).unwrap()).unwrap(), request);
+        assert!(decode(b"no header").is_err());
+        assert!(decode(b"{\"url\":\"x\"}\n").is_err());
+    }
+
+    #[tokio::test]
+    async fn retries_until_accepted() {
+        let (url, bodies) = stub(&[503, 500, 200]);
+        let dir = temp_dir("retry");
+        uploader(url, 3, Some(dir.clone())).upload(&profile(), &window()).await.unwrap();
+        assert_eq!(bodies.lock().unwrap().len(), 3);
+        assert!(spooled(&dir).unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn rejected_is_dropped() {
+        let (url, bodies) = stub(&[400]);
+        let dir = temp_dir("rejected");
+        uploader(url, 3, Some(dir.clone())).upload(&profile(), &window()).await.unwrap();
+        assert_eq!(bodies.lock().unwrap().len(), 1);
+        assert!(spooled(&dir).unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn spools_after_retries() {
+        let (url, bodies) = stub(&[503]);
+        let dir = temp_dir("spool");
+        let uploader = uploader(url.clone(), 1, Some(dir.clone()));
+        assert!(uploader.upload(&profile(), &window()).await.is_err());
+        assert_eq!(bodies.lock().unwrap().len(), 2);
+
+        let paths = spooled(&dir).unwrap();
+        assert_eq!(paths.len(), 1);
+        let spooled = decode(&fs::read(&paths[0]).unwrap()).unwrap();
+        assert_eq!(spooled.url, format!("{url}/ingest"));
+        assert_eq!(spooled.body, bodies.lock().unwrap()[0]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn spool_evicts_oldest() {
+        let dir = temp_dir("evict");
+        for body in [b"a", b"b", b"c"] {
+            spool(&dir, &request("http://localhost", body), 2).unwrap();
+        }
+        let bodies: Vec<_> =
+            spooled(&dir).unwrap().iter().map(|p| decode(&fs::read(p).unwrap()).unwrap().body).collect();
+        assert_eq!(bodies, [b"b", b"c"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn flushes_spool_in_order() {
+        let (url, bodies) = stub(&[200]);
+        let dir = temp_dir("flush");
+        for body in [b"first", b"secnd"] {
+            spool(&dir, &request(&url, body), 10).unwrap();
+        }
+        uploader(url, 0, Some(dir.clone())).upload(&profile(), &window()).await.unwrap();
+
+        let bodies = bodies.lock().unwrap();
+        assert_eq!(bodies.len(), 3);
+        assert_eq!(bodies[0], b"first");
+        assert_eq!(bodies[1], b"secnd");
+        assert!(spooled(&dir).unwrap().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn flush_stops_at_unavailable() {
+        let (url, bodies) = stub(&[503]);
+        let dir = temp_dir("flush-stop");
+        for body in [b"first", b"secnd"] {
+            spool(&dir, &request(&url, body), 10).unwrap();
+        }
+        assert!(uploader(url, 0, Some(dir.clone())).upload(&profile(), &window()).await.is_err());
+
+        // 第一个补发失败后不再尝试第二个，新的 profile 也进入 spool
+        let bodies = bodies.lock().unwrap();
+        assert_eq!(bodies.len(), 2);
+        assert_eq!(bodies[0], b"first");
+        assert_eq!(spooled(&dir).unwrap().len(), 3);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,192 @@
+use std::collections::HashMap;
+
+use crate::output::proto::Message;
+use crate::profile::{Frame, FrameKind, Profile, Unit};
+use crate::upload::Window;
+
+/// 编码 OTLP `ExportProfilesServiceRequest`，对应 opentelemetry-proto v1.7 的
+/// `v1development` profiles 协议。该协议仍在演进，接收端版本不一致时可能拒收。
+/// 各张表的第 0 项均为零值
+pub fn encode(profile: &Profile, window: &Window, resource: &[(String, String)]) -> Vec<u8> {
+    let mut d = Dictionary::default();
+
+    let (kind, unit) = match profile.unit {
+        Unit::Samples => ("samples", "count"),
+        Unit::Nanoseconds => ("time", "nanoseconds"),
+        Unit::Bytes => ("space", "bytes"),
+    };
+    let sample_type = d.value_type(kind, unit);
+
+    let mut out = Message::default();
+    let mut location_indices = Vec::new();
+    let mut samples = Vec::new();
+    for stack in &profile.stacks {
+        let mut sample = Message::default();
+        let mut attributes = Vec::new();
+        let start = location_indices.len();
+        for frame in stack.symbols().iter().rev() {
+            match frame.kind {
+                FrameKind::Process => attributes.push(d.attribute("process.executable.name", &frame.name)),
+                FrameKind::Thread => attributes.push(d.attribute("thread.name", &frame.name)),
+                _ => location_indices.push(d.location(frame)),
+            }
+        }
+        sample.varint(1, start as u64);
+        sample.varint(2, (location_indices.len() - start) as u64);
+        sample.packed(3, &[stack.weight]);
+        sample.packed(4, &attributes);
+        samples.push(sample);
+    }
+
+    let mut p = Message::default();
+    p.message(1, &sample_type);
+    for sample in &samples {
+        p.message(2, sample);
+    }
+    p.packed(3, &location_indices);
+    p.varint(4, window.start_ns());
+    p.varint(5, window.duration_ns());
+    if let Some(period) = 1_000_000_000u64.checked_div(window.sample_rate) {
+        p.message(6, &d.value_type("cpu", "nanoseconds"));
+        p.varint(7, period);
+    }
+
+    let mut scope = Message::default();
+    scope.bytes(1, b"larkspur");
+    scope.bytes(2, env!("CARGO_PKG_VERSION").as_bytes());
+    let mut scope_profiles = Message::default();
+    scope_profiles.message(1, &scope);
+    scope_profiles.message(2, &p);
+
+    let mut res = Message::default();
+    for (key, value) in resource {
+        res.message(1, &key_value(key, value));
+    }
+    let mut resource_profiles = Message::default();
+    resource_profiles.message(1, &res);
+    resource_profiles.message(2, &scope_profiles);
+
+    out.message(1, &resource_profiles);
+    out.message(2, &d.finish());
+    out.buf
+}
+
+fn key_value(key: &str, value: &str) -> Message {
+    let mut any = Message::default();
+    any.bytes(1, value.as_bytes());
+    let mut kv = Message::default();
+    kv.bytes(1, key.as_bytes());
+    kv.message(2, &any);
+    kv
+}
+
+/// `ProfilesDictionary`：所有 profile 共享的字符串、函数、位置和属性表
+struct Dictionary {
+    strings: Vec<String>,
+    string_index: HashMap<String, u64>,
+    functions: Vec<Message>,
+    function_index: HashMap<(String, Option<String>), u64>,
+    locations: Vec<Message>,
+    location_index: HashMap<Frame, u64>,
+    attributes: Vec<Message>,
+    attribute_index: HashMap<(String, String), u64>,
+}
+
+impl Default for Dictionary {
+    fn default() -> Self {
+        Dictionary {
+            strings: vec![String::new()],
+            string_index: HashMap::from([(String::new(), 0)]),
+            functions: vec![Message::default()],
+            function_index: HashMap::new(),
+            locations: vec![Message::default()],
+            location_index: HashMap::new(),
+            attributes: vec![Message::default()],
+            attribute_index: HashMap::new(),
+        }
+    }
+}
+
+impl Dictionary {
+    fn string(&mut self, s: &str) -> u64 {
+        if let Some(&i) = self.string_index.get(s) {
+            return i;
+        }
+        let i = self.strings.len() as u64;
+        self.strings.push(s.to_string());
+        self.string_index.insert(s.to_string(), i);
+        i
+    }
+
+    fn value_type(&mut self, kind: &str, unit: &str) -> Message {
+        let mut m = Message::default();
+        m.varint(1, self.string(kind));
+        m.varint(2, self.string(unit));
+        m
+    }
+
+    fn attribute(&mut self, key: &str, value: &str) -> u64 {
+        let k = (key.to_string(), value.to_string());
+        if let Some(&i) = self.attribute_index.get(&k) {
+            return i;
+        }
+        let i = self.attributes.len() as u64;
+        self.attributes.push(key_value(key, value));
+        self.attribute_index.insert(k, i);
+        i
+    }
+
+    fn function(&mut self, frame: &Frame) -> u64 {
+        let key = (frame.folded(), frame.file.clone());
+        if let Some(&i) = self.function_index.get(&key) {
+            return i;
+        }
+        let mut m = Message::default();
+        let name = self.string(&key.0);
+        m.varint(1, name);
+        m.varint(2, name);
+        if let Some(file) = &key.1 {
+            m.varint(3, self.string(file));
+        }
+        let i = self.functions.len() as u64;
+        self.functions.push(m);
+        self.function_index.insert(key, i);
+        i
+    }
+
+    fn location(&mut self, frame: &Frame) -> u64 {
+        if let Some(&i) = self.location_index.get(frame) {
+            return i;
+        }
+        let mut line = Message::default();
+        line.varint(1, self.function(frame));
+        if let Some(n) = frame.line {
+            line.varint(2, n as u64);
+        }
+        let mut m = Message::default();
+        m.message(3, &line);
+        let i = self.locations.len() as u64;
+        self.locations.push(m);
+        self.location_index.insert(frame.clone(), i);
+        i
+    }
+
+    fn finish(self) -> Message {
+        let mut m = Message::default();
+        // mapping_table 只有零值项
+        m.message(1, &Message::default());
+        for location in &self.locations {
+            m.message(2, location);
+        }
+        for function in &self.functions {
+            m.message(3, function);
+        }
+        for s in &self.strings {
+            m.bytes(5, s.as_bytes());
+        }
+        for attribute in &self.attributes {
+            m.message(6, attribute);
+        }
+        m
+    }
+}
@@ -28,6 +28,9 @@ fn main() -> anyhow::Result<()> {
         Commands::BuildAll => {
             build_ebpf("larkspur-ebpf-on-cpu")?;
             build_ebpf("larkspur-ebpf-off-cpu")?;
+            build_ebpf("larkspur-ebpf-trace")?;
+            build_ebpf("larkspur-ebpf-alloc")?;
+            build_ebpf("larkspur-ebpf-runqlat")?;
             build_user()?;
             Ok(())
         }